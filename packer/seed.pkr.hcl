@@ -28,12 +28,18 @@ build {
   }
 
   provisioner "file" {
-    sources = ["barley-register", "zap-disk", "attach-disk"]
+    sources = ["barley-register", "barley-refresh", "barley-reconcile", "barley-pull", "zap-disk", "attach-disk"]
     destination = "/usr/local/bin/"
   }
 
   provisioner "file" {
-    sources = ["barley-machine-key.service", "barley-register.service", "ssh-host-key.service"]
+    sources = [
+      "barley-machine-key.service",
+      "barley-register.service",
+      "barley-refresh.service",
+      "barley-refresh.timer",
+      "ssh-host-key.service",
+    ]
     destination = "/etc/systemd/system/"
   }
 
@@ -47,19 +53,24 @@ build {
       "sed 's/--network-veth/--network-bridge=br0/' /lib/systemd/system/systemd-nspawn@.service > /etc/systemd/system/systemd-nspawn@.service",
       "sed -i 's/^#*SystemMaxUse=.*$/SystemMaxUse=32M/' /etc/systemd/journald.conf",
       "rm /etc/ssh/ssh_host_*",
+      "ssh-keygen -k -f /etc/ssh/revoked.krl",
       "adduser --system --group --disabled-login --home /var/lib/barley barley",
       "chmod 755 /usr/local/bin/barley-register",
+      "chmod 755 /usr/local/bin/barley-refresh",
+      "chmod 755 /usr/local/bin/barley-reconcile",
+      "chmod 755 /usr/local/bin/barley-pull",
       "chmod 755 /usr/local/bin/zap-disk",
       "chmod 755 /usr/local/bin/attach-disk",
-      "systemctl enable barley-machine-key barley-register ssh-host-key",
+      "systemctl enable barley-machine-key barley-register barley-refresh.timer ssh-host-key",
       "install -d -m 700 /root/.ssh",
     ]
   }
 
   post-processors {
     post-processor "shell-local" {
-      environment_vars = ["MACHINE=seed"]
-      script = "make-initramfs"
+      inline = [
+        "target/release/barley-initramfs --firmware /lib/firmware --vmlinuz seed.vmlinuz /var/lib/machines/seed seed.cpio.zst",
+      ]
     }
 
     post-processor "shell-local" {
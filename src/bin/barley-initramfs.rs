@@ -0,0 +1,143 @@
+use std::{env, fs, io};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use structopt::StructOpt;
+
+use barley::{human_size, print_table};
+use barley::cpio::{self, Rootfs, Writer};
+use barley::dpkg;
+
+/// Build the Seed initramfs from a rootfs
+#[derive(StructOpt)]
+struct Opt {
+    /// Rootfs directory or tarball (.tar, .tar.zst, .tar.xz, or .tar.gz)
+    #[structopt(parse(from_os_str))]
+    rootfs: PathBuf,
+
+    /// Initramfs to write, e.g. seed.cpio.zst
+    #[structopt(parse(from_os_str))]
+    output: PathBuf,
+
+    /// Copy the newest kernel from /boot of the rootfs here, e.g. seed.vmlinuz
+    #[structopt(long, parse(from_os_str))]
+    vmlinuz: Option<PathBuf>,
+
+    /// Directory with intel-ucode and amd-ucode, default: /lib/firmware of the rootfs
+    #[structopt(long, parse(from_os_str))]
+    firmware: Option<PathBuf>,
+
+    /// Modification time of every file, default: $SOURCE_DATE_EPOCH or 0
+    #[structopt(long)]
+    mtime: Option<u32>,
+
+    /// zstd compression level
+    #[structopt(long, default_value = "3")]
+    level: u32,
+
+    /// Directory depth of the size breakdown
+    #[structopt(long, default_value = "2")]
+    depth: usize,
+
+    /// Number of directories in the size breakdown
+    #[structopt(long, default_value = "20")]
+    top: usize,
+}
+
+// decompress a rootfs tarball with the tool its magic calls for
+fn open_tarball(path: &PathBuf) -> (Box<dyn Read>, Option<Child>) {
+    let mut input = BufReader::new(File::open(path).unwrap_or_else(|err| panic!("Failed to open {:?}: {}", path, err)));
+    let magic = input.fill_buf().unwrap().to_vec();
+    let decompress = if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        "zstd"
+    } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0]) {
+        "xz"
+    } else if magic.starts_with(&[0x1f, 0x8b]) {
+        "gzip"
+    } else {
+        return (Box::new(input), None);
+    };
+    let mut child = Command::new(decompress).arg("-dc")
+        .stdin(File::open(path).unwrap())
+        .stdout(Stdio::piped())
+        .spawn().unwrap();
+    (Box::new(child.stdout.take().unwrap()), Some(child))
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    let mtime = opt.mtime
+        .or_else(|| env::var("SOURCE_DATE_EPOCH").ok().and_then(|t| t.parse().ok()))
+        .unwrap_or(0);
+    let mut rootfs = if opt.rootfs.is_dir() {
+        Rootfs::from_dir(&opt.rootfs).unwrap()
+    } else {
+        // file contents wait in an unlinked file next to the output until
+        // they are written in order
+        let spill_path = PathBuf::from(format!("{}.spill", opt.output.to_str().unwrap()));
+        let spill = OpenOptions::new().read(true).write(true).create(true).truncate(true)
+            .open(&spill_path).unwrap();
+        fs::remove_file(&spill_path).unwrap();
+        let (input, child) = open_tarball(&opt.rootfs);
+        let rootfs = Rootfs::from_tar(input, spill).unwrap();
+        if let Some(mut child) = child {
+            if !child.wait().unwrap().success() {
+                panic!("Failed to decompress {:?}", &opt.rootfs);
+            }
+        }
+        rootfs
+    };
+
+    if let Some(vmlinuz) = &opt.vmlinuz {
+        let kernel = rootfs.entries.iter()
+            .filter(|e| e.is_file() && e.path.starts_with("boot/vmlinuz-"))
+            .max_by(|a, b| dpkg::compare_versions(&a.path[13..], &b.path[13..]))
+            .expect("No kernel found in /boot");
+        println!("Copying {} to {:?}", &kernel.path, vmlinuz);
+        io::copy(&mut rootfs.open(kernel).unwrap().take(kernel.size), &mut File::create(vmlinuz).unwrap())
+            .unwrap();
+    }
+    rootfs.entries.retain(|e| e.path != "boot" && !e.path.starts_with("boot/"));
+
+    let microcode = match &opt.firmware {
+        Some(dir) => Rootfs::from_dir(dir).unwrap().microcode(""),
+        None => rootfs.microcode("usr/lib/firmware").and_then(|m| match m.is_empty() {
+            true  => rootfs.microcode("lib/firmware"),
+            false => Ok(m),
+        }),
+    }.unwrap();
+    if microcode.is_empty() {
+        eprintln!("No microcode found, install intel-microcode and amd64-microcode or use --firmware");
+    }
+
+    let mut out = File::create(&opt.output).unwrap();
+    if !microcode.is_empty() {
+        out = cpio::write_microcode(out, &microcode, mtime).unwrap();
+    }
+    let mut zstd = Command::new("zstd").arg("-q").arg(format!("-{}", opt.level)).arg("-c")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::from(out))
+        .spawn().unwrap();
+    let mut writer = Writer::new(BufWriter::new(zstd.stdin.take().unwrap()), mtime);
+    rootfs.write(&mut writer).unwrap();
+    writer.finish().unwrap().flush().unwrap();
+    if !zstd.wait().unwrap().success() {
+        panic!("zstd failed");
+    }
+
+    let total: u64 = rootfs.entries.iter().filter(|e| e.is_file()).map(|e| e.size).sum();
+    let sizes: Vec<Vec<String>> = rootfs.sizes(opt.depth).into_iter().take(opt.top)
+        .map(|(dir, size)| vec![
+            dir,
+            human_size(size),
+            format!("{:.1}%", size as f64 * 100.0 / total.max(1) as f64),
+        ])
+        .collect();
+    print_table(&sizes, "files", &["DIRECTORY", "SIZE", "SHARE"], |r| r.iter().map(|f| f.as_str()).collect());
+    for (name, blob) in microcode.iter() {
+        println!("Microcode {}: {}", name, human_size(blob.len() as u64));
+    }
+    println!("{} entries, {} of files, {:?}: {}", rootfs.entries.len(), human_size(total),
+        &opt.output, human_size(fs::metadata(&opt.output).unwrap().len()));
+}
@@ -1,15 +1,29 @@
 use chrono::Local;
-use std::{env, ffi, fs};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::{env, ffi, fs, io};
 use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::path::PathBuf;
+use std::os::unix::fs::PermissionsExt;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use structopt::StructOpt;
 use version_compare::Cmp;
 
-use barley::{Data, Error, print_table, tls, ToResult};
+use barley::{Assignment, Data, Error, human_size, now, parse_duration, parse_size, print_table, random_pw, schedule, secret,
+             SeedInfo, ssh, tls, ToResult, valid_label};
+use barley::build::Spec;
+use barley::cpio::{self, Rootfs};
+use barley::dpkg;
+use barley::image::{self, Contents, METADATA, Metadata, Tag};
+use barley::manifest::{self, Action, Current, MachineSpec, Manifest, Volume};
+use barley::oci::OciImage;
+use barley::schedule::Requirements;
+use barley::security::Tracker;
+use barley::ssh::AdminKey;
 
 fn home() -> PathBuf {
     match env::var("HOME") {
@@ -34,37 +48,78 @@ fn images_home() -> PathBuf {
     home_barley().join("images")
 }
 
+// sha256sum(1) manifest of all imported images
+fn image_sums() -> PathBuf {
+    images_home().join("SHA256SUMS")
+}
+
+// import time of every image, hard links keep the mtime of the original
+fn image_imports() -> PathBuf {
+    images_home().join("IMPORTS")
+}
+
+// history of tag moves, the last move of a tag is its current version
+fn image_tags() -> PathBuf {
+    images_home().join("TAGS")
+}
+
+fn tag_history() -> Vec<Tag> {
+    Tag::parse_history(&fs::read_to_string(image_tags()).unwrap_or_default())
+}
+
+// snapshot of https://security-tracker.debian.org/tracker/data/json
+fn security_tracker() -> PathBuf {
+    home_barley().join("debian-security.json")
+}
+
+fn ssh_config() -> PathBuf {
+    home_barley().join("ssh_config")
+}
+
 fn update_ssh_config() {
     let config_path = home_ssh().join("config");
-    let mut config = fs::read_to_string(&config_path).unwrap_or("".to_string());
-    if let None = config.lines().find(|&s| s == "Host seed-*" ) {
+    let config = fs::read_to_string(&config_path).unwrap_or("".to_string());
+    let include = format!("Include {}", ssh_config().to_str().unwrap());
+    if config.lines().find(|&s| s == include).is_none() {
         if !config.is_empty() {
             let mut backup = config_path.clone();
             backup.set_file_name("config.barley-backup");
             fs::metadata(&backup).expect_err(
-                "SSH config is missing a 'Host seed-*' line, but backup already exists. \
+                "SSH config is missing Barley Include line, but backup already exists. \
                  Something must have gone wrong, please clean up your ~/.ssh/ manually."
             );
             fs::copy(&config_path, &backup).unwrap();
         }
-        if !config.ends_with('\n') {
-            config.push('\n');
-        }
-        config.push_str("Host seed-*\n\tUser root\n\tStrictHostKeyChecking yes\n");
-        fs::write(&config_path, &config).unwrap();
+        // drop the global block written by earlier versions of sow
+        let config = config.replace("Host seed-*\n\tUser root\n\tStrictHostKeyChecking yes\n", "");
+        // Include has to come before any Host blocks to apply to all hosts
+        let config = format!("{}\n\n{}", include, config);
+        fs::write(&config_path, config.trim_end().to_string() + "\n").unwrap();
+    }
+}
+
+fn write_ssh_config() {
+    let mut config = String::from("# Generated by sow, do not edit\n");
+    for field in Field::all() {
+        config.push_str(&field.ssh_config());
+    }
+    if fs::read_to_string(ssh_config()).unwrap_or_default() != config {
+        fs::write(ssh_config(), config).unwrap();
     }
 }
 
 fn setup() {
-    if let Err(_) = fs::metadata(home_ssh()) {
+    if fs::metadata(home_ssh()).is_err() {
         panic!("Missing ~/.ssh. Run ssh-keygen -t ed25519.");
     }
     update_ssh_config();
     Data::new(home_barley()).unwrap();
     Data::new(fields_home()).unwrap();
     Data::new(images_home()).unwrap();
+    write_ssh_config();
 }
 
+#[derive(Clone)]
 struct Field {
     name: String,
     modified: SystemTime,
@@ -90,14 +145,22 @@ impl Field {
         Self::all().max_by_key(|f| f.modified)
     }
 
+    fn select(name: Option<String>) -> Self {
+        match name {
+            Some(f) => Field::new(&f),
+            None    => Field::latest().expect("No Barley fields found. Run 'sow new <name>'."),
+        }
+    }
+
     fn all() -> impl Iterator<Item=Field> {
-        fs::read_dir(&fields_home()).unwrap()
+        // no fields directory before the first 'sow new'
+        fs::read_dir(fields_home()).into_iter().flatten()
             .filter_map(|entry| Self::from_dir(&entry.unwrap()))
     }
 
     fn create(&self, key: &PathBuf) -> String {
         let path = self.path();
-        if let Ok(_) = fs::metadata(&path) {
+        if fs::metadata(&path).is_ok() {
             panic!("Field '{}' already exists", &self.name);
         }
         Data::new(path).unwrap();
@@ -107,12 +170,412 @@ impl Field {
         tls::generate_root(&self.name, &self.cakey(), &self.cacert()).unwrap()
     }
 
+    // Look for the root.key password everywhere it could have been stored,
+    // None means that certtool will have to prompt for it.
+    fn password(&self, fd: Option<i32>) -> Option<String> {
+        if let Some(fd) = fd {
+            return Some(secret::from_fd(fd).expect("Failed to read root.key password"));
+        }
+        if let Ok(pw) = env::var("BARLEY_ROOT_PASSWORD") {
+            return Some(pw);
+        }
+        if let Some(pw) = secret::keyring_lookup(&self.name) {
+            return Some(pw);
+        }
+        if self.envelope().is_file() {
+            match secret::envelope_open(&self.name, &self.envelope_key(), &self.envelope()) {
+                Ok(pw)   => return Some(pw),
+                Err(err) => eprintln!("Failed to open {:?}: {}", self.envelope(), err),
+            }
+        }
+        None
+    }
+
+    // Returns false when the password hasn't been stored anywhere.
+    fn store_password(
+        &self,
+        pw: &str,
+        keyring: bool,
+        envelope: Option<PathBuf>,
+    ) -> Result<bool, Error> {
+        let mut stored = false;
+        if keyring || secret::keyring_lookup(&self.name).is_some() {
+            secret::keyring_store(&self.name, pw)?;
+            stored = true;
+        }
+        if let Some(key) = envelope {
+            fs::copy(&key, self.envelope_key())?;
+        }
+        if self.envelope_key().is_file() {
+            secret::envelope_seal(&self.name, &self.envelope_key(), &self.envelope(), pw)?;
+            stored = true;
+        }
+        Ok(stored)
+    }
+
+    fn passwd(&self, old: Option<String>) -> Result<String, Error> {
+        if old.is_none() {
+            println!("When prompted, enter current root.key password for {}", &self.name);
+        }
+        let pw = random_pw();
+        // the previous root shares the password until it is rotated out
+        let prevkey = self.file("root.new.prev.key");
+        let result = match self.prevkey().is_file() {
+            true  => fs::copy(self.prevkey(), &prevkey).map_err(Error::from)
+                .and_then(|_| tls::change_password(&prevkey, old.as_deref(), &pw)),
+            false => Ok(()),
+        }.and_then(|_| {
+            if old.is_none() && prevkey.is_file() {
+                println!("When prompted, enter current root.key password for {} again", &self.name);
+            }
+            tls::change_password(&self.cakey(), old.as_deref(), &pw)
+        });
+        if let Err(err) = result {
+            fs::remove_file(&prevkey).ok();
+            return Err(err);
+        }
+        if prevkey.is_file() {
+            fs::rename(&prevkey, self.prevkey())?;
+        }
+        Ok(pw)
+    }
+
+    fn rotate_ca(&self, old: Option<String>) -> Result<String, Error> {
+        let stamp = Local::now().format("%Y%m%d%H%M%S").to_string();
+        let id = format!("{} {}", &self.name, &stamp);
+        let key = self.file("root.new.key");
+        let cert = self.file("root.new.crt");
+        let prevkey = self.file("root.new.prev.key");
+        let result = tls::generate_root(&id, &key, &cert).and_then(|pw| {
+            if old.is_none() {
+                println!("When prompted, enter current root.key password for {}", &self.name);
+            }
+            tls::cross_sign(
+                &id,
+                &self.cacert(),
+                &self.cakey(),
+                old.as_deref(),
+                &cert,
+                &self.crosscert(),
+            )?;
+            // keep the outgoing key under the one password that is stored
+            if old.is_none() {
+                println!("When prompted, enter current root.key password for {} again", &self.name);
+            }
+            fs::copy(self.cakey(), &prevkey)?;
+            tls::change_password(&prevkey, old.as_deref(), &pw)?;
+            Ok(pw)
+        });
+        let pw = match result {
+            Ok(pw)   => pw,
+            Err(err) => {
+                for file in &[&key, &cert, &prevkey] {
+                    fs::remove_file(file).ok();
+                }
+                return Err(err);
+            },
+        };
+        if fs::metadata(self.prevcert()).is_ok() {
+            // only two roots are trusted at a time, retire the oldest one
+            fs::rename(self.prevcert(), self.file(&format!("root.retired-{}.crt", &stamp)))?;
+            fs::rename(self.prevkey(), self.file(&format!("root.retired-{}.key", &stamp)))?;
+        }
+        fs::rename(self.cacert(), self.prevcert())?;
+        fs::rename(&prevkey, self.prevkey())?;
+        fs::rename(&cert, self.cacert())?;
+        fs::rename(&key, self.cakey())?;
+        let mut bundle = fs::read_to_string(self.cacert())?;
+        bundle.push_str(&fs::read_to_string(self.prevcert())?);
+        fs::write(self.file("bundle.crt"), bundle)?;
+        Ok(pw)
+    }
+
+    // run a script on a Seed, or locally when seed is None
+    fn command(&self, seed: &Option<String>, script: &str) -> Command {
+        match seed {
+            Some(seed) => {
+                let host = self.ssh_host(seed);
+                println!("Running ssh {} '{}'", &host, &script);
+                let mut c = Command::new("/usr/bin/ssh");
+                c.arg(&host).arg(script);
+                c
+            },
+            None => {
+                println!("Running sudo sh -c '{}'", &script);
+                let mut c = Command::new("/usr/bin/sudo");
+                c.arg("/bin/sh").arg("-c").arg(script);
+                c
+            },
+        }
+    }
+
+    fn running(&self, seed: &Option<String>) -> Result<Vec<String>, Error> {
+        let output = self.command(seed, "machinectl list --no-legend").output()?;
+        if !output.status.success() {
+            return Err(Error::CommandError(format!("machinectl list failed: {:?}", output.status)));
+        }
+        Ok(String::from_utf8(output.stdout)?.lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .map(|name| name.to_string())
+            .collect())
+    }
+
+    fn machine(&self, name: &str) -> Machine {
+        Machine::load(self, name).unwrap_or_else(|| panic!(
+            "Machine '{}' not found in field '{}'", &name, &self.name))
+    }
+
+    // Seeds registered with Sower go by their name in ssh_config, any other
+    // host or ssh alias is passed to ssh as is
+    fn ssh_host(&self, seed: &str) -> String {
+        match self.seeds().iter().any(|s| s.name == seed) {
+            true  => self.seed_host(seed),
+            false => seed.to_string(),
+        }
+    }
+
+    fn seed_host(&self, seed: &str) -> String {
+        format!("{}.{}", seed, &self.name)
+    }
+
+    fn known_hosts(&self) -> PathBuf {
+        self.file("known_hosts")
+    }
+
+    fn ssh_config(&self) -> String {
+        let mut config = String::new();
+        for seed in self.seeds() {
+            let host = self.seed_host(&seed.name);
+            config.push_str(&format!("\nHost {}\n\tHostName {}\n\tHostKeyAlias {}\n",
+                &host, &seed.ip, &host));
+        }
+        config.push_str(&format!("\nHost {}\n\tUser root\n\tStrictHostKeyChecking yes\n\
+                                  \tUserKnownHostsFile {}\n",
+            self.seed_host("seed-*"), self.known_hosts().to_str().unwrap()));
+        config
+    }
+
+    fn write_known_hosts(&self) -> Result<(), Error> {
+        let mut known_hosts = String::new();
+        for machine in self.machines() {
+            if let Ok(key) = machine.data.read("ca.pub") {
+                let key: Vec<&str> = key.split_whitespace().take(2).collect();
+                known_hosts.push_str(&format!("@cert-authority {} {} {}\n",
+                    self.seed_host("seed-*"), key.join(" "), &machine.name));
+            }
+        }
+        fs::write(self.known_hosts(), known_hosts)?;
+        Ok(())
+    }
+
+    // registered Seeds as of the last refresh_seeds()
+    fn seeds(&self) -> Vec<SeedInfo> {
+        fs::read_to_string(self.file("seeds")).unwrap_or_default().lines()
+            .filter_map(|line| {
+                let mut f = line.splitn(2, '\t');
+                Some(SeedInfo {
+                    name: f.next()?.to_string(),
+                    ip: f.next()?.to_string(),
+                    labels: Vec::new(),
+                    status: None,
+                })
+            })
+            .collect()
+    }
+
+    // registered Seeds with their latest status, straight from Sower
+    fn query_seeds(&self) -> Result<Vec<SeedInfo>, Error> {
+        let sower = self.sower().ok_or(format!("Sower machine not found in field '{}'", &self.name))?;
+        let seeds: Vec<SeedInfo> = serde_json::from_slice(&sower.output("barley seeds")?)
+            .map_err(|err| Error::from(format!("Failed to parse Seeds: {}", err)))?;
+        let cache: String = seeds.iter().map(|s| format!("{}\t{}\n", s.name, s.ip)).collect();
+        fs::write(self.file("seeds"), cache)?;
+        Ok(seeds)
+    }
+
+    fn refresh_seeds(&self) -> Result<(), Error> {
+        self.query_seeds().map(|_| ())
+    }
+
+    fn schedule(&self, policy: &str, req: &Requirements) -> Result<String, Error> {
+        let seeds = self.query_seeds()?;
+        write_ssh_config();
+        let seed = schedule::pick(&*schedule::policy(policy)?, req, &seeds, now())
+            .ok_or("No healthy Seeds with enough free memory and disk found.")?;
+        let status = seed.status.as_ref().unwrap();
+        println!("Picked {} with {} policy: {} memory free, {} disk free, load {:.2}, {} machines",
+            &seed.name, &policy, human_size(status.mem_free), human_size(status.disk_free),
+            status.load, status.machines);
+        Ok(seed.name.to_string())
+    }
+
+    // Seeds that run machines from an anti-affinity group
+    fn group_seeds(&self, group: &str) -> Vec<String> {
+        self.machines().into_iter()
+            .filter(|m| m.group.as_deref() == Some(group))
+            .filter_map(|m| m.seed)
+            .collect()
+    }
+
+    fn sower(&self) -> Option<Machine> {
+        self.machines().into_iter().find(|m| m.image.name == "sower")
+    }
+
+    // upload an image to Sower once, Seeds pull it from there
+    fn push_image(&self, image: &Image) -> Result<(), Error> {
+        let sower = self.sower().ok_or(format!("Sower machine not found in field '{}'", &self.name))?;
+        let file = format!("images/{}", image.stem());
+        // checksum is uploaded last, the image is complete once it's there
+        if sower.run(&format!("test -f /var/lib/barley/{}.sha256", &file)).to_result().is_ok() {
+            return Ok(());
+        }
+        let sha256 = image.sha256()?;
+        sower.update(&image.path(), &format!("{}.tar.zst", &file), "644")?;
+        if let Some(signature) = image.signature() {
+            sower.update(&signature, &format!("{}.tar.zst.sig", &file), "644")?;
+        }
+        sower.write(sha256.as_bytes(), &format!("{}.sha256", &file), "644")
+    }
+
+    // remove image versions no machine of the field uses from Sower, and their
+    // templates from Seeds
+    fn clean_images(&self) {
+        let machines = self.machines();
+        let used = |seed: Option<&Option<String>>| -> Vec<String> {
+            machines.iter()
+                .filter(|m| seed.map(|s| &m.seed == s).unwrap_or(true))
+                .flat_map(|m| vec![Some(m.image.clone()), m.previous()])
+                .flatten()
+                .map(|i| i.stem())
+                .collect()
+        };
+        let mut seeds: Vec<Option<String>> = self.seeds().into_iter().map(|s| Some(s.name)).collect();
+        if machines.iter().any(|m| m.seed.is_none()) {
+            seeds.push(None);
+        }
+        for seed in seeds {
+            // images and templates on this host are shared by the local machines of all fields
+            let keep = match &seed {
+                Some(_) => used(Some(&seed)),
+                None    => images_in_use().into_iter().map(|(stem, _)| stem).collect(),
+            }.join(" ");
+            // versions with the same content share a template
+            let script = format!(
+                "cd /var/lib/barley/images 2>/dev/null || exit 0; \
+                 keep=; \
+                 for m in {}; do [ -f $m ] && keep=\"$keep $(cut -c1-32 $m)\"; done; \
+                 for m in *; do \
+                   [ -f \"$m\" ] || continue; \
+                   case \" {} \" in *\" $m \"*) continue;; esac; \
+                   t=$(cut -c1-32 \"$m\"); \
+                   case \"$keep \" in *\" $t \"*) ;; \
+                     *) if machinectl show-image .barley-$t >/dev/null 2>&1; then \
+                          machinectl remove .barley-$t; \
+                        fi;; \
+                   esac; \
+                   rm \"$m\"; \
+                 done", &keep, &keep);
+            if let Err(err) = self.command(&seed, &script).to_result() {
+                eprintln!("Failed to remove stale images from {}: {}",
+                    seed.as_deref().unwrap_or("localhost"), err);
+            }
+        }
+        if let Some(sower) = self.sower() {
+            // checksum goes first, the image is incomplete without it
+            let script = format!(
+                "cd /var/lib/barley/images 2>/dev/null || exit 0; \
+                 for f in *.sha256; do \
+                   [ -f \"$f\" ] || continue; \
+                   s=${{f%.sha256}}; \
+                   case \" {} \" in *\" $s \"*) ;; *) rm -f $s.sha256 $s.tar.zst $s.tar.zst.sig;; esac; \
+                 done", used(None).join(" "));
+            if let Err(err) = sower.run(&script).to_result() {
+                eprintln!("Failed to remove stale images from Sower: {}", err);
+            }
+        }
+    }
+
+    fn build_keys(&self) -> PathBuf {
+        self.file("build_keys")
+    }
+
+    // initramfs overlays relative to the field directory, same paths on Sower
+    fn overlays(&self) -> Vec<String> {
+        let mut overlays = vec![String::from("overlays/field.cpio")];
+        if let Ok(dir) = fs::read_dir(self.file("overlays/profiles")) {
+            let mut profiles: Vec<String> = dir
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .filter(|name| name.ends_with(".cpio"))
+                .map(|name| format!("overlays/profiles/{}", name))
+                .collect();
+            profiles.sort();
+            overlays.extend(profiles);
+        }
+        overlays.into_iter().filter(|o| self.file(o).is_file()).collect()
+    }
+
+    // images must be signed once the field has a build key
+    fn trusted(&self) -> bool {
+        !ssh::build_keys(&self.build_keys()).is_empty()
+    }
+
+    fn verify(&self, path: &PathBuf, signature: Option<&PathBuf>) -> Result<(), Error> {
+        let trusted = self.trusted();
+        match signature {
+            Some(signature) => {
+                let principal = ssh::verify(&self.build_keys(), signature, path)?;
+                println!("Good signature from build key '{}'", principal);
+                Ok(())
+            },
+            None if trusted => Err(Error::from(format!(
+                "{:?} is not signed, field '{}' requires images signed with a build key", path, &self.name))),
+            None => Ok(()),
+        }
+    }
+
+    fn admins(&self) -> Vec<AdminKey> {
+        ssh::admin_keys(&self.admin()).unwrap()
+    }
+
+    fn write_admins(&self, keys: &[AdminKey]) -> Result<(), Error> {
+        ssh::write_admin_keys(&self.admin(), keys)?;
+        self.push("admin.pub")
+    }
+
+    // copy a file from the field directory to the running Sower
+    fn push(&self, file: &str) -> Result<(), Error> {
+        match self.sower() {
+            Some(sower) => sower.update(&self.file(file), file, "644"),
+            None => {
+                eprintln!("Sower machine not found in field '{}', {} \
+                           will be updated next time it is started.", &self.name, file);
+                Ok(())
+            },
+        }
+    }
+
+    fn ssh_certs(&self) -> Data {
+        Data::new(self.file("ssh-certs")).unwrap()
+    }
+
+    fn machines(&self) -> Vec<Machine> {
+        let mut machines: Vec<Machine> = fs::read_dir(self.path()).unwrap()
+            .filter_map(|entry| Machine::load(self, entry.unwrap().file_name().to_str().unwrap()))
+            .collect();
+        machines.sort_by(|a, b| a.name.cmp(&b.name));
+        machines
+    }
+
     fn path(&self) -> PathBuf {
         fields_home().join(&self.name)
     }
 
+    fn data(&self) -> Data {
+        Data::new(self.path()).unwrap()
+    }
+
     fn file(&self, name: &str) -> PathBuf {
-        self.path().join(&name)
+        self.path().join(name)
     }
 
     fn admin(&self) -> PathBuf {
@@ -126,6 +589,105 @@ impl Field {
     fn cakey(&self) -> PathBuf {
         self.file("root.key")
     }
+
+    fn prevcert(&self) -> PathBuf {
+        self.file("root.prev.crt")
+    }
+
+    fn prevkey(&self) -> PathBuf {
+        self.file("root.prev.key")
+    }
+
+    fn crosscert(&self) -> PathBuf {
+        self.file("cross.crt")
+    }
+
+    fn krl(&self) -> PathBuf {
+        self.file("revoked.krl")
+    }
+
+    fn envelope(&self) -> PathBuf {
+        self.file("root.key.age")
+    }
+
+    fn envelope_key(&self) -> PathBuf {
+        self.file("envelope.pub")
+    }
+
+    // root.crt until the first rotation, both roots after that
+    fn bundle(&self) -> PathBuf {
+        let bundle = self.file("bundle.crt");
+        match fs::metadata(&bundle) {
+            Ok(_)  => bundle,
+            Err(_) => self.cacert(),
+        }
+    }
+
+    // machines of the field together with machines that were not started by sow
+    fn states(&self) -> Vec<MachineState> {
+        let machines = self.machines();
+        let mut seeds: Vec<Option<String>> = machines.iter().map(|m| m.seed.clone()).collect();
+        seeds.sort();
+        seeds.dedup();
+        let mut list = Vec::new();
+        for seed in seeds {
+            let running = self.running(&seed);
+            let state = |name: &str| match &running {
+                Ok(r) if r.iter().any(|n| n == name) => "running",
+                Ok(_)  => "stopped",
+                Err(_) => "unknown",
+            }.to_string();
+            let seed_name = seed.clone().unwrap_or("local".to_string());
+            for m in machines.iter().filter(|m| m.seed == seed) {
+                list.push(MachineState {
+                    name: m.name.to_string(),
+                    image: m.image.name.to_string(),
+                    version: m.image.version.to_string(),
+                    seed: seed_name.to_string(),
+                    state: state(&m.name),
+                });
+            }
+            // machines that were not started by sow
+            for name in running.iter().flatten() {
+                if !machines.iter().any(|m| m.seed == seed && &m.name == name) {
+                    list.push(MachineState {
+                        name: name.to_string(),
+                        image: String::new(),
+                        version: String::new(),
+                        seed: seed_name.to_string(),
+                        state: state(name),
+                    });
+                }
+            }
+        }
+        list
+    }
+
+    fn current(&self) -> Vec<Current> {
+        let machines = self.machines();
+        // labels are only known while Sower is reachable
+        let seeds = self.query_seeds().ok();
+        self.states().into_iter()
+            .filter(|m| !m.image.is_empty())
+            .map(|m| {
+                let machine = machines.iter().find(|v| v.name == m.name && v.seed.as_deref().unwrap_or("local") == m.seed);
+                Current {
+                    volumes: machine.map(|v| v.volumes()).unwrap_or_default(),
+                    network: machine.and_then(|v| v.network()),
+                    ca: machine.map(|v| v.has_ca()).unwrap_or(false),
+                    group: machine.and_then(|v| v.group.clone()),
+                    labels: seeds.as_ref().and_then(|seeds| seeds.iter()
+                        .find(|s| s.name == m.seed)
+                        .map(|s| s.labels.clone())),
+                    running: m.state == "running",
+                    seed: Some(m.seed).filter(|s| s != "local"),
+                    name: m.name,
+                    image: m.image,
+                    version: m.version,
+                }
+            })
+            .collect()
+    }
 }
 
 fn ls() {
@@ -134,13 +696,268 @@ fn ls() {
     print_table(&fields, "fields", &["FIELD"], |f| vec![&f.name]);
 }
 
-fn new(name: String, key: Option<PathBuf>) {
+fn new(name: String, key: Option<PathBuf>, keyring: bool, envelope: Option<PathBuf>) {
     let field = Field::new(&name);
     let key = key.unwrap_or(home_ssh().join("id_ed25519.pub"));
     let pw = field.create(&key);
-    println!("{} root.key password: {}", &name, &pw);
+    if !field.store_password(&pw, keyring, envelope).unwrap() {
+        println!("{} root.key password: {}", &name, &pw);
+    }
+}
+
+fn passwd(field: Option<String>, pass_fd: Option<i32>, keyring: bool, envelope: Option<PathBuf>) {
+    let field = Field::select(field);
+    let pw = field.passwd(field.password(pass_fd)).unwrap();
+    if !field.store_password(&pw, keyring, envelope).unwrap() {
+        println!("{} root.key password: {}", &field.name, &pw);
+    }
+}
+
+struct MachineState {
+    name: String,
+    image: String,
+    version: String,
+    seed: String,
+    state: String,
+}
+
+fn ls_machines(field: Option<String>) {
+    let list = Field::select(field).states();
+    print_table(&list, "machines", &["MACHINE", "IMAGE", "VERSION", "SEED", "STATE"], |m| vec![
+        &m.name, &m.image, &m.version, &m.seed, &m.state,
+    ]);
+}
+
+fn label(field: Option<String>, seed: String, labels: Vec<String>, rm: Vec<String>) {
+    let field = Field::select(field);
+    let mut script = format!("barley label {}", &seed);
+    for l in labels.iter() {
+        script.push_str(&format!(" {}", l));
+    }
+    for l in rm.iter() {
+        script.push_str(&format!(" --rm {}", l));
+    }
+    if let Some(l) = labels.iter().chain(rm.iter()).find(|l| !valid_label(l)) {
+        panic!("Invalid label '{}', use letters, digits, and _.=-", l);
+    }
+    field.sower().expect("Sower machine not found in field")
+        .run(&script).to_result().unwrap();
+}
+
+fn ls_seeds(field: Option<String>) {
+    let seeds = Field::select(field).query_seeds().unwrap();
+    let now = now();
+    let list: Vec<Vec<String>> = seeds.iter().map(|s| {
+        let health = if schedule::healthy(s, now) { "healthy" } else { "stale" };
+        match &s.status {
+            Some(st) => vec![
+                s.name.to_string(), s.ip.to_string(), s.labels.join(","),
+                human_size(st.mem_free), human_size(st.mem_total), st.cpus.to_string(),
+                format!("{:.2}", st.load), human_size(st.disk_free), st.machines.to_string(),
+                health.to_string(),
+            ],
+            None => vec![s.name.to_string(), s.ip.to_string(), s.labels.join(",")],
+        }
+    }).collect();
+    print_table(&list, "Seeds",
+        &["SEED", "IP", "LABELS", "FREE", "MEMORY", "CPUS", "LOAD", "DISK", "MACHINES", "HEALTH"],
+        |s| s.iter().map(|f| f.as_str()).collect());
+}
+
+fn refresh_ssh_config(field: Option<String>) {
+    let field = Field::select(field);
+    field.refresh_seeds().unwrap();
+    field.write_known_hosts().unwrap();
+    write_ssh_config();
+}
+
+fn ls_admins(field: Option<String>) {
+    let keys = Field::select(field).admins();
+    print_table(&keys, "admin keys", &["COMMENT", "PRINCIPALS", "EXPIRES", "KEY"], |k| vec![
+        k.comment(),
+        k.option("principals"),
+        k.option("expiry-time"),
+        &k.key,
+    ]);
+}
+
+fn add_admin(
+    field: Option<String>,
+    key: PathBuf,
+    principals: Option<String>,
+    expiry: Option<String>,
+) {
+    let field = Field::select(field);
+    let key = match fs::read_to_string(&key) {
+        Ok(k)    => AdminKey::new(&k, principals, expiry),
+        Err(err) => panic!("Failed to read admin public key {:?}: {}", key, err),
+    };
+    if AdminKey::parse(&key.to_line()).is_none() {
+        panic!("Not a valid SSH public key: {}", &key.key);
+    }
+    let mut keys: Vec<AdminKey> = field.admins().into_iter()
+        .filter(|k| k.key != key.key)
+        .collect();
+    keys.push(key);
+    field.write_admins(&keys).unwrap();
+}
+
+fn rm_admin(field: Option<String>, comment: String) {
+    let field = Field::select(field);
+    let keys = field.admins();
+    let remaining: Vec<AdminKey> = keys.iter()
+        .filter(|k| k.comment() != comment)
+        .cloned()
+        .collect();
+    if remaining.len() == keys.len() {
+        panic!("Admin key '{}' not found in field '{}'", &comment, &field.name);
+    }
+    if remaining.is_empty() {
+        panic!("Refusing to remove the last admin key from field '{}'", &field.name);
+    }
+    field.write_admins(&remaining).unwrap();
+}
+
+struct SshCert {
+    serial: String,
+    issued: String,
+    id: String,
+    principals: String,
+    validity: String,
+    revoked: bool,
+}
+
+impl SshCert {
+    fn all(field: &Field) -> Vec<Self> {
+        let data = field.ssh_certs();
+        let revoked = data.read("revoked").unwrap_or_default();
+        let revoked: Vec<&str> = revoked.lines().collect();
+        data.read("log").unwrap_or_default().lines()
+            .filter_map(|line| {
+                let f: Vec<&str> = line.split('\t').collect();
+                if f.len() < 5 {
+                    return None;
+                }
+                Some(SshCert {
+                    serial: f[0].to_string(),
+                    issued: f[1].to_string(),
+                    id: f[2].to_string(),
+                    principals: f[3].to_string(),
+                    validity: f[4].to_string(),
+                    revoked: revoked.contains(&f[0]),
+                })
+            })
+            .collect()
+    }
+
+    fn next_serial(field: &Field) -> u64 {
+        Self::all(field).iter().filter_map(|c| c.serial.parse::<u64>().ok()).max().unwrap_or(0) + 1
+    }
+
+    fn path(field: &Field, serial: &str) -> PathBuf {
+        field.ssh_certs().file(&format!("{}-cert.pub", serial))
+    }
+}
+
+fn ls_ssh_certs(field: Option<String>) {
+    let certs = SshCert::all(&Field::select(field));
+    print_table(&certs, "SSH certificates",
+        &["SERIAL", "ISSUED", "ID", "PRINCIPALS", "VALIDITY", "REVOKED"], |c| vec![
+            &c.serial, &c.issued, &c.id, &c.principals, &c.validity,
+            if c.revoked { "yes" } else { "" },
+        ]);
+}
+
+// private key of a field admin key in ~/.ssh, or its public key to sign
+// with ssh-agent when the private key is elsewhere
+fn admin_ca(field: &Field) -> PathBuf {
+    let admins: Vec<String> = field.admins().iter()
+        .map(|k| k.key.split_whitespace().take(2).collect::<Vec<&str>>().join(" "))
+        .collect();
+    let mut keys: Vec<PathBuf> = fs::read_dir(home_ssh()).unwrap()
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension() == Some("pub".as_ref()))
+        .filter(|path| fs::read_to_string(path)
+            .map(|k| admins.contains(&k.split_whitespace().take(2).collect::<Vec<&str>>().join(" ")))
+            .unwrap_or(false))
+        .collect();
+    keys.sort();
+    let key = keys.into_iter().next().unwrap_or_else(|| panic!(
+        "No admin key of field '{}' found in ~/.ssh, use --ca", &field.name));
+    match key.with_extension("").is_file() {
+        true  => key.with_extension(""),
+        false => key,
+    }
+}
+
+fn sign_ssh_cert(field: Option<String>, opt: SignOpt) {
+    let field = Field::select(field);
+    let SignOpt { key, ca, id, principals, validity, force_command, source_address } = opt;
+    let ca = ca.unwrap_or_else(|| admin_ca(&field));
+    let id = id.unwrap_or(key.file_stem().unwrap().to_str().unwrap().to_string());
+    let mut options = Vec::new();
+    if let Some(c) = force_command {
+        options.push(format!("force-command={}", c));
+    }
+    if let Some(a) = source_address {
+        options.push(format!("source-address={}", a));
+    }
+    let serial = SshCert::next_serial(&field);
+    let cert = ssh::sign_user(&id, &ca, &principals, &validity, serial, &options, &key).unwrap();
+    fs::copy(&cert, SshCert::path(&field, &serial.to_string())).unwrap();
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(field.ssh_certs().file("log")).unwrap()
+        .write_all(format!("{}\t{}\t{}\t{}\t{}\n",
+            serial, Local::now().format("%Y-%m-%d %H:%M"), &id, &principals, &validity,
+        ).as_bytes()).unwrap();
+    println!("Signed {:?} with serial {}", &cert, serial);
+}
+
+fn revoke_ssh_cert(field: Option<String>, serial: String) {
+    let field = Field::select(field);
+    if fs::metadata(SshCert::path(&field, &serial)).is_err() {
+        panic!("SSH certificate with serial {} not found in field '{}'", &serial, &field.name);
+    }
+    let mut revoked: Vec<String> = SshCert::all(&field).into_iter()
+        .filter(|c| c.revoked)
+        .map(|c| c.serial)
+        .collect();
+    if !revoked.contains(&serial) {
+        revoked.push(serial);
+    }
+    let certs: Vec<PathBuf> = revoked.iter().map(|s| SshCert::path(&field, s)).collect();
+    fs::remove_file(field.krl()).ok();
+    ssh::revoke(&field.krl(), &certs).unwrap();
+    field.ssh_certs().write("revoked", &revoked.iter().map(|s| format!("{}\n", s)).collect::<String>()).unwrap();
+    field.push("revoked.krl").unwrap();
+}
+
+fn rotate_ca(field: Option<String>, pass_fd: Option<i32>) {
+    let field = Field::select(field);
+    let pw = field.rotate_ca(field.password(pass_fd)).unwrap();
+    if !field.store_password(&pw, false, None).unwrap() {
+        println!("{} new root.key password: {}", &field.name, &pw);
+    }
+    for machine in field.machines().iter().filter(|m| m.has_ca()) {
+        if let Err(err) = machine.reissue_ca(&pw) {
+            eprintln!("Failed to re-issue certificate for machine '{}': {}", &machine.name, err);
+        }
+    }
+    // machines started by earlier versions of sow don't record their image
+    // and Seed, so there is nowhere to send their certificate
+    for entry in fs::read_dir(field.path()).unwrap() {
+        let path = entry.unwrap().path();
+        if path.join("machine.key").is_file() && !path.join("image").is_file() {
+            eprintln!("Certificate of machine '{}' was not re-issued, its Seed is unknown. \
+                       Start it again with 'sow start --ca' before the old root expires.",
+                path.file_name().unwrap().to_str().unwrap());
+        }
+    }
 }
 
+#[derive(Clone)]
 struct Image {
     name: String,
     version: String,
@@ -155,9 +972,9 @@ impl Image {
         Self::from_metadata(&entry.metadata().unwrap(), &entry.file_name())
     }
 
-    fn from_path(path: &PathBuf) -> Option<Self> {
+    fn from_path(path: &Path) -> Option<Self> {
         match path.metadata() {
-            Ok(m)  => Self::from_metadata(&m, &path.file_name().unwrap()),
+            Ok(m)  => Self::from_metadata(&m, path.file_name().unwrap()),
             Err(_) => None,
         }
     }
@@ -166,15 +983,44 @@ impl Image {
         let file_name = file_name.to_str().unwrap();
         // ~/.barley/images/name_version.tar.zst
         if metadata.is_file() && file_name.ends_with(".tar.zst") {
-            let mut parts = file_name.strip_suffix(".tar.zst").unwrap().splitn(2, '_');
-            let name = parts.next().unwrap();
-            let version = parts.next().unwrap_or("");
-            Some(Self::new(name, version))
+            Some(Self::parse(file_name.strip_suffix(".tar.zst").unwrap()))
         } else {
             None
         }
     }
 
+    fn parse(stem: &str) -> Self {
+        let mut parts = stem.splitn(2, '_');
+        let name = parts.next().unwrap();
+        let version = parts.next().unwrap_or("");
+        Self::new(name, version)
+    }
+
+    // name[:tag] with an optional version or tag, default: latest version
+    fn resolve(image: &str, version: Option<String>) -> Option<Self> {
+        let (name, version) = match image.splitn(2, ':').collect::<Vec<&str>>()[..] {
+            [name, tag] => (name, Some(tag.to_string())),
+            _           => (image, version),
+        };
+        match version {
+            Some(v) => Some(Self::new(name, &Self::tagged(name, &v).unwrap_or(v))),
+            None    => Self::latest(name),
+        }
+    }
+
+    fn tagged(name: &str, tag: &str) -> Option<String> {
+        Tag::current(&tag_history()).into_iter()
+            .find(|t| t.image == name && t.tag == tag)
+            .map(|t| t.version.to_string())
+    }
+
+    fn tags(&self) -> Vec<String> {
+        Tag::current(&tag_history()).into_iter()
+            .filter(|t| t.image == self.name && t.version == self.version)
+            .map(|t| t.tag.to_string())
+            .collect()
+    }
+
     fn latest(name: &str) -> Option<Self> {
         Self::all()
             .filter(|i| i.name == name)
@@ -183,7 +1029,7 @@ impl Image {
 
     fn generate_version(&self) -> String {
         let version = Local::now().format("%Y%m%d").to_string();
-        if let None = Self::from_path(&self.path()) {
+        if Self::from_path(&self.path()).is_none() {
             return version;
         }
         let mut base = Self::all()
@@ -212,44 +1058,867 @@ impl Image {
     }
 
     fn all() -> impl Iterator<Item=Image> {
-        fs::read_dir(&images_home()).unwrap()
+        fs::read_dir(images_home()).unwrap()
             .filter_map(|entry| Self::from_dir(&entry.unwrap()))
     }
 
+    fn stem(&self) -> String {
+        format!("{}_{}", &self.name, &self.version)
+    }
+
     fn path(&self) -> PathBuf {
-        images_home().join(format!("{}_{}.tar.zst", &self.name, &self.version))
+        images_home().join(format!("{}.tar.zst", self.stem()))
+    }
+
+    fn file_name(&self) -> String {
+        format!("{}.tar.zst", self.stem())
+    }
+
+    // detached ssh-keygen -Y signature made with a build key
+    fn signature(&self) -> Option<PathBuf> {
+        Some(images_home().join(format!("{}.sig", self.file_name()))).filter(|s| s.is_file())
+    }
+
+    // hidden image on the Seed that machines of this image version are cloned from
+    fn template(&self) -> Result<String, Error> {
+        Ok(format!(".barley-{}", &self.sha256()?[..32]))
+    }
+
+    fn digest(&self) -> Result<String, Error> {
+        let mut hasher = Sha256::new();
+        io::copy(&mut File::open(self.path())?, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    // checksum recorded at import, images imported by earlier versions of
+    // sow are recorded the first time they are used
+    fn sha256(&self) -> Result<String, Error> {
+        let suffix = format!("  {}", self.file_name());
+        let sums = fs::read_to_string(image_sums()).unwrap_or_default();
+        if let Some(line) = sums.lines().find(|l| l.ends_with(&suffix)) {
+            return Ok(line.trim_end_matches(&suffix).to_string());
+        }
+        let sha256 = self.digest()?;
+        OpenOptions::new().create(true).append(true).open(image_sums())?
+            .write_all(format!("{}{}\n", &sha256, &suffix).as_bytes())?;
+        Ok(sha256)
+    }
+
+    // image.toml extracted at import, None for images without one
+    fn metadata(&self) -> Option<Metadata> {
+        let metadata = fs::read_to_string(images_home().join(format!("{}.toml", self.stem()))).ok()?;
+        Metadata::parse(&metadata).ok()
+    }
+
+    // images imported by earlier versions of sow fall back to the mtime
+    fn imported(&self) -> SystemTime {
+        let imports = fs::read_to_string(image_imports()).unwrap_or_default();
+        match image::import_time(&imports, &self.stem()) {
+            Some(time) => SystemTime::UNIX_EPOCH + Duration::from_secs(time),
+            None => self.path().metadata().and_then(|m| m.modified()).unwrap_or(SystemTime::UNIX_EPOCH),
+        }
+    }
+
+    // machine of any field or a tag that keeps this version from being removed
+    fn used_by(&self, used: &[(String, String)]) -> Option<String> {
+        used.iter().find(|(stem, _)| stem == &self.stem()).map(|(_, by)| by.to_string())
+    }
+
+    fn remove(&self) -> Result<(), Error> {
+        for file in &[self.file_name(), format!("{}.sig", self.file_name()), format!("{}.toml", self.stem())] {
+            let path = images_home().join(file);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        let suffix = format!("  {}", self.file_name());
+        let sums: String = fs::read_to_string(image_sums()).unwrap_or_default().lines()
+            .filter(|l| !l.ends_with(&suffix))
+            .map(|l| format!("{}\n", l))
+            .collect();
+        fs::write(image_sums(), sums)?;
+        println!("Removed {} {}", &self.name, &self.version);
+        Ok(())
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        if self.digest()? != self.sha256()? {
+            return Err(Error::from(format!(
+                "Image {} {} does not match its SHA256 checksum", &self.name, &self.version)));
+        }
+        Ok(())
     }
 }
 
 fn ls_images() {
     let mut images: Vec<Image> = Image::all().collect();
     images.sort_by(|a, b| a.name.cmp(&b.name).then(a.version.cmp(&b.version)));
-    print_table(&images, "images", &["IMAGE", "VERSION"], |i| vec![&i.name, &i.version]);
+    let rows: Vec<Vec<String>> = images.into_iter().map(|i| {
+        let m = i.metadata().unwrap_or_default();
+        let ports: Vec<String> = m.ports.iter().map(|p| p.to_string()).collect();
+        let volumes: Vec<String> = m.volumes.iter().map(|v| v.to_string()).collect();
+        let tags = i.tags().join(",");
+        vec![
+            i.name,
+            i.version,
+            tags,
+            m.built.unwrap_or_default(),
+            m.commit.unwrap_or_default(),
+            ports.join(","),
+            volumes.join(","),
+        ]
+    }).collect();
+    print_table(&rows, "images", &["IMAGE", "VERSION", "TAGS", "BUILT", "COMMIT", "PORTS", "VOLUMES"], |r| {
+        r.iter().map(|f| f.as_str()).collect()
+    });
 }
 
-fn import(path: PathBuf) {
-    match Image::from_path(&path) {
+fn resolve_image(name: &str, version: Option<String>) -> Image {
+    let image = Image::resolve(name, version).unwrap_or_else(|| panic!(
+        "No images found for '{}'. Run 'sow import <path>'.", name));
+    if Image::from_path(&image.path()).is_none() {
+        panic!("Image {} {} not found", &image.name, &image.version);
+    }
+    image
+}
+
+fn show_image(name: String, version: Option<String>, json: bool) {
+    let image = resolve_image(&name, version);
+    let metadata = image.metadata().unwrap_or_default();
+    let contents = Contents::list(&image.path()).unwrap();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&serde_json::json!({
+            "name": &image.name,
+            "version": &image.version,
+            "sha256": image.sha256().unwrap(),
+            "built": &metadata.built,
+            "base": &metadata.base,
+            "commit": &metadata.commit,
+            "packages": &contents.packages,
+        })).unwrap());
+        return;
+    }
+    let tags = image.tags().join(", ");
+    let volumes: Vec<String> = metadata.volumes.iter().map(|v| v.to_string()).collect();
+    let ports: Vec<String> = metadata.ports.iter().map(|p| p.to_string()).collect();
+    let files = contents.files.len().to_string();
+    for (key, value) in &[
+        ("Image", Some(image.name.to_string())),
+        ("Version", Some(image.version.to_string())),
+        ("SHA256", Some(image.sha256().unwrap())),
+        ("Tags", Some(tags).filter(|t| !t.is_empty())),
+        ("Built", metadata.built.clone()),
+        ("Base", metadata.base.clone()),
+        ("Commit", metadata.commit.clone()),
+        ("Network", metadata.network.clone()),
+        ("Ports", Some(ports.join(", ")).filter(|p| !p.is_empty())),
+        ("Volumes", Some(volumes.join(", ")).filter(|v| !v.is_empty())),
+        ("Files", Some(files)),
+    ] {
+        if let Some(value) = value {
+            println!("{:8} {}", format!("{}:", key), value);
+        }
+    }
+    println!();
+    print_table(&contents.packages, "Debian packages", &["PACKAGE", "VERSION", "ARCH", "SOURCE"], |p| vec![
+        &p.name, &p.version, &p.arch, &p.source,
+    ]);
+}
+
+fn diff_images(name: String, from: String, to: String) {
+    let old = resolve_image(&name, Some(from));
+    let new = resolve_image(&name, Some(to));
+    println!("Comparing {} {} to {}", &name, &old.version, &new.version);
+    let old = Contents::read(&old.path()).unwrap();
+    let new = Contents::read(&new.path()).unwrap();
+    let packages = dpkg::diff(&old.packages, &new.packages);
+    let files = old.diff(&new);
+    if packages.is_empty() && files.is_empty() {
+        println!("No changes.");
+        return;
+    }
+    for change in packages.iter() {
+        println!("{}", change);
+    }
+    if !packages.is_empty() && !files.is_empty() {
+        println!();
+    }
+    for (change, path) in files {
+        println!("{} /{}", change, path);
+    }
+}
+
+// latest version of every image and the versions machines are running
+fn audit_images(name: Option<String>, tracker: Option<PathBuf>, release: Option<String>, all: bool) {
+    let tracker = tracker.unwrap_or(security_tracker());
+    let tracker = match Tracker::load(&tracker) {
+        Ok(t)    => t,
+        Err(err) => panic!("{}\nDownload https://security-tracker.debian.org/tracker/data/json to {:?}",
+            err, &tracker),
+    };
+    // running machines only, like 'sow machines' shows them
+    let mut machines: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for field in Field::all() {
+        for machine in field.states().into_iter().filter(|m| m.state == "running" && !m.image.is_empty()) {
+            machines.entry(Image::new(&machine.image, &machine.version).stem()).or_default().push(machine.name);
+        }
+    }
+    let mut images: Vec<Image> = Image::all()
+        .filter(|i| name.as_ref().map(|n| &i.name == n).unwrap_or(true))
+        .filter(|i| machines.contains_key(&i.stem())
+            || Image::latest(&i.name).map(|l| l.version == i.version).unwrap_or(false))
+        .collect();
+    images.sort_by(|a, b| a.name.cmp(&b.name).then(Image::compare_versions(&a.version, &b.version)));
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    for image in images.iter() {
+        let contents = Contents::read(&image.path()).unwrap();
+        let release = match release.clone().or(contents.release) {
+            Some(r) => r,
+            None    => {
+                eprintln!("Skipping {} {}, its Debian release is unknown, use --release",
+                    &image.name, &image.version);
+                continue;
+            },
+        };
+        let used_by = machines.get(&image.stem()).map(|m| m.join(",")).unwrap_or_default();
+        for v in tracker.check(&release, &contents.packages, all) {
+            rows.push(vec![
+                image.name.to_string(),
+                image.version.to_string(),
+                used_by.to_string(),
+                v.id,
+                v.package,
+                v.installed,
+                v.fixed.unwrap_or(String::from("none")),
+                v.urgency,
+            ]);
+        }
+    }
+    print_table(&rows, "known vulnerabilities",
+        &["IMAGE", "VERSION", "MACHINES", "ISSUE", "PACKAGE", "INSTALLED", "FIXED", "URGENCY"],
+        |r| r.iter().map(|f| f.as_str()).collect());
+}
+
+// image stems used by machines of all fields and by tags
+fn images_in_use() -> Vec<(String, String)> {
+    let mut used = Vec::new();
+    for field in Field::all() {
+        for machine in field.machines() {
+            used.push((machine.image.stem(), format!("machine '{}'", &machine.name)));
+            if let Some(previous) = machine.previous() {
+                used.push((previous.stem(), format!("machine '{}' for rollback", &machine.name)));
+            }
+        }
+    }
+    for tag in Tag::current(&tag_history()) {
+        used.push((format!("{}_{}", &tag.image, &tag.version), format!("tag '{}'", &tag.tag)));
+    }
+    used
+}
+
+fn rm_images(name: String, version: Option<String>) {
+    let images: Vec<Image> = Image::all()
+        .filter(|i| i.name == name && version.as_ref().map(|v| &i.version == v).unwrap_or(true))
+        .collect();
+    if images.is_empty() {
+        panic!("Image {} {} not found", &name, version.as_deref().unwrap_or(""));
+    }
+    let used = images_in_use();
+    for image in images {
+        match image.used_by(&used) {
+            Some(by) => println!("Keeping {} {}, used by {}", &image.name, &image.version, by),
+            None     => image.remove().unwrap(),
+        }
+    }
+    for field in Field::all() {
+        field.clean_images();
+    }
+}
+
+fn prune_images(keep: usize, older_than: Option<u64>, dry_run: bool) {
+    let used = images_in_use();
+    let names: BTreeSet<String> = Image::all().map(|i| i.name).collect();
+    let cutoff = SystemTime::now() - Duration::from_secs(older_than.unwrap_or(0));
+    for name in names {
+        let mut images: Vec<Image> = Image::all().filter(|i| i.name == name).collect();
+        images.sort_by(|a, b| Image::compare_versions(&b.version, &a.version));
+        for image in images.into_iter().skip(keep) {
+            if image.imported() > cutoff || image.used_by(&used).is_some() {
+                continue;
+            }
+            match dry_run {
+                true  => println!("Would remove {} {}", &image.name, &image.version),
+                false => image.remove().unwrap(),
+            }
+        }
+    }
+    if !dry_run {
+        for field in Field::all() {
+            field.clean_images();
+        }
+    }
+}
+
+fn push(field: Option<String>, name: String, version: Option<String>) {
+    let image = resolve_image(&name, version);
+    Field::select(field).push_image(&image).unwrap();
+}
+
+fn tag_image(name: String, version: String, tag: String) {
+    if !Tag::valid(&tag) {
+        panic!("Invalid tag '{}', tags start with a letter", &tag);
+    }
+    let image = Image::new(&name, &version);
+    if Image::from_path(&image.path()).is_none() {
+        panic!("Image {} {} not found", &name, &version);
+    }
+    let tag = Tag {
+        time: Local::now().format("%Y-%m-%dT%H:%M:%S").to_string(),
+        image: name,
+        tag,
+        version,
+    };
+    OpenOptions::new().create(true).append(true).open(image_tags()).unwrap()
+        .write_all(format!("{}\n", &tag).as_bytes()).unwrap();
+    println!("Tagged {} {} as {}", &tag.image, &tag.version, &tag.tag);
+}
+
+fn ls_tags(image: Option<String>, history: bool) {
+    let all = tag_history();
+    let tags: Vec<&Tag> = match history {
+        true  => all.iter().collect(),
+        false => Tag::current(&all),
+    };
+    let tags: Vec<&Tag> = tags.into_iter()
+        .filter(|t| image.as_ref().map(|i| &t.image == i).unwrap_or(true))
+        .collect();
+    print_table(&tags, "tags", &["IMAGE", "TAG", "VERSION", "TAGGED"], |t| vec![
+        &t.image, &t.tag, &t.version, &t.time,
+    ]);
+}
+
+// scratch directory in the image store, so that imports can hard link from it
+fn staging() -> PathBuf {
+    let dir = images_home().join(format!(".import-{}", random_pw()));
+    fs::create_dir(&dir).unwrap();
+    dir
+}
+
+// stdin, URL, unpacked rootfs directory, or tarball that isn't zstd yet
+fn import_stream(
+    field: Option<String>,
+    source: String,
+    name: Option<String>,
+    version: Option<String>,
+    signature: Option<PathBuf>,
+) {
+    let mut image = match source.as_str() {
+        "-" => Image::new(&name.clone().expect("Image name is required to import from stdin"), ""),
+        _   => {
+            let (name, version) = image::source_name(&source);
+            Image::new(&name, &version)
+        },
+    };
+    image.name = name.clone().unwrap_or(image.name);
+    image.version = version.clone().unwrap_or(image.version);
+    check_image_name(&image);
+    let url = source.starts_with("http://") || source.starts_with("https://");
+    let mut child = None;
+    let input: Box<dyn Read> = if source == "-" {
+        Box::new(io::stdin())
+    } else if url || Path::new(&source).is_dir() {
+        let mut command = match url {
+            true  => Command::new("curl"),
+            false => Command::new("sudo"),
+        };
+        match url {
+            true  => command.arg("-fsSL").arg(&source),
+            false => command.arg("tar").arg("-C").arg(&source).arg("-cf").arg("-").arg("."),
+        };
+        let mut c = command.stdout(Stdio::piped()).spawn().unwrap();
+        let stdout = c.stdout.take().unwrap();
+        child = Some(c);
+        Box::new(stdout)
+    } else {
+        Box::new(File::open(&source).unwrap())
+    };
+    let dir = staging();
+    let rootfs = dir.join(image.file_name());
+    println!("Importing {} as {} {}", &source, &image.name, &image.version);
+    let result = image::recompress(input, &rootfs).and_then(|_| {
+        if let Some(mut child) = child {
+            if !child.wait()?.success() {
+                return Err(Error::from(format!("Failed to read {}", &source)));
+            }
+        }
+        Ok(())
+    });
+    if let Err(err) = result {
+        fs::remove_dir_all(&dir).unwrap();
+        panic!("Failed to import {}: {}", &source, err);
+    }
+    // detached signature published next to the image
+    let signature = match (signature, url) {
+        (None, true) => {
+            let sig = dir.join(format!("{}.sig", image.file_name()));
+            Command::new("curl").arg("-fsSL").arg("-o").arg(&sig).arg(format!("{}.sig", &source))
+                .status().ok().filter(|s| s.success()).map(|_| sig)
+        },
+        (signature, _) => signature,
+    };
+    import_rootfs(field, rootfs, name, version, signature);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// flatten into a rootfs tarball and import that
+fn import_oci(
+    field: Option<String>,
+    path: PathBuf,
+    name: Option<String>,
+    version: Option<String>,
+    signature: Option<PathBuf>,
+) {
+    if signature.is_some() {
+        panic!("--signature does not apply to OCI images, their rootfs is only created on import");
+    }
+    let oci = OciImage::open(&path).unwrap();
+    let stem = path.file_stem().and_then(|s| OciImage::image_name(s.to_str()?));
+    let mut image = Image::new(
+        &name.clone().or(oci.name.clone()).or(stem).expect("Unable to name the image, use --name"),
+        &version.clone().or(oci.tag.clone()).unwrap_or_default(),
+    );
+    if image.version.is_empty() {
+        image.version = image.generate_version();
+    }
+    check_image_name(&image);
+    if Image::from_path(&image.path()).is_some() {
+        panic!("Image version {} already exists", &image.version);
+    }
+    let dir = staging();
+    let rootfs = dir.join(image.file_name());
+    println!("Converting {:?} to {} {}", &path, &image.name, &image.version);
+    let mut zstd = Command::new("zstd").arg("-qT0").arg("-o").arg(&rootfs)
+        .stdin(Stdio::piped())
+        .spawn().unwrap();
+    let result = oci.write_rootfs(zstd.stdin.take().unwrap(), &oci.metadata(&image.name, &image.version))
+        .map(drop)
+        .and_then(|_| match zstd.wait()?.success() {
+            true  => Ok(()),
+            false => Err(Error::from("zstd failed")),
+        });
+    if let Err(err) = result {
+        fs::remove_dir_all(&dir).unwrap();
+        panic!("Failed to convert {:?}: {}", &path, err);
+    }
+    import_rootfs(field, rootfs, None, None, None);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// names and versions from the command line end up in file names
+fn check_image_name(image: &Image) {
+    if !image::valid_name(&image.name) {
+        panic!("Invalid image name '{}', use letters, digits, and .+-", &image.name);
+    }
+    if !image.version.is_empty() && !image::valid_version(&image.version) {
+        panic!("Invalid image version '{}', use letters, digits, and _.+~-", &image.version);
+    }
+}
+
+fn import_rootfs(
+    field: Option<String>,
+    path: PathBuf,
+    name: Option<String>,
+    version: Option<String>,
+    signature: Option<PathBuf>,
+) {
+    match Image::from_path(&path) {
         Some(mut image) => {
+            // name and version declared by the image win over the file name,
+            // the ones given on the command line win over both
+            let mut metadata = Metadata::read(&path).unwrap();
+            if let Some(m) = &metadata {
+                image.name = m.name.to_string();
+                image.version = m.version.clone().unwrap_or(image.version);
+            }
+            image.name = name.unwrap_or(image.name);
+            image.version = version.unwrap_or(image.version);
             if image.version.is_empty() {
                 image.version = image.generate_version();
             }
-            if let Some(_) = Image::from_path(&image.path()) {
+            check_image_name(&image);
+            if Image::from_path(&image.path()).is_some() {
                 panic!("Image version {} already exists", &image.version);
             }
-            if let Err(err) = fs::hard_link(&path, &image.path()) {
+            // images are checked against the build keys of the field, if there is one
+            let field = match field {
+                Some(f) => Some(Field::new(&f)),
+                None    => Field::latest(),
+            };
+            let trusted = field.as_ref().map(|f| f.trusted()).unwrap_or(false);
+            // a signature next to the image only counts when there are build keys to check it with
+            let signature = signature.or(Some(PathBuf::from(format!("{}.sig", path.to_str().unwrap())))
+                .filter(|s| trusted && s.is_file()));
+            match (&field, &signature) {
+                (Some(field), _) => field.verify(&path, signature.as_ref()).unwrap(),
+                (None, Some(signature)) => panic!(
+                    "No Barley field to verify {:?} with. Run 'sow new <name>' first.", signature),
+                (None, None) => {},
+            }
+            if let Err(err) = fs::hard_link(&path, image.path()) {
                 eprintln!("Failed to create hard link at {:?}: {:?}", &image.path(), err);
-                fs::copy(&path, &image.path()).unwrap();
+                fs::copy(&path, image.path()).unwrap();
+            }
+            if let Some(signature) = signature {
+                fs::copy(&signature, images_home().join(format!("{}.sig", image.file_name()))).unwrap();
             }
+            OpenOptions::new().create(true).append(true).open(image_imports())
+                .and_then(|mut f| f.write_all(format!("{} {}\n", now(), image.stem()).as_bytes()))
+                .unwrap();
+            if let Some(m) = metadata.as_mut() {
+                m.name = image.name.to_string();
+                m.version = Some(image.version.to_string());
+                fs::write(images_home().join(format!("{}.toml", image.stem())), m.to_toml().unwrap()).unwrap();
+            }
+            println!("Imported {} {} with SHA256 {}", &image.name, &image.version, image.sha256().unwrap());
         },
         None => panic!("{:?} is not a valid image file", path),
     }
 }
 
+fn import(
+    field: Option<String>,
+    source: String,
+    name: Option<String>,
+    version: Option<String>,
+    signature: Option<PathBuf>,
+) {
+    let path = PathBuf::from(&source);
+    if source == "-" || source.starts_with("http://") || source.starts_with("https://") {
+        import_stream(field, source, name, version, signature)
+    } else if OciImage::is_oci(&path) {
+        import_oci(field, path, name, version, signature)
+    } else if source.ends_with(".tar.zst") {
+        import_rootfs(field, path, name, version, signature)
+    } else if path.exists() {
+        import_stream(field, source, name, version, signature)
+    } else {
+        panic!("{:?} not found", path)
+    }
+}
+
+// run a command as root on this host, printing it like Field::command does
+fn sudo(args: &[&str]) -> Result<(), Error> {
+    println!("Running sudo {}", args.join(" "));
+    match Command::new("/usr/bin/sudo").args(args).status()?.success() {
+        true  => Ok(()),
+        false => Err(Error::CommandError(format!("{} failed", args.join(" ")))),
+    }
+}
+
+// run a shell script from the host inside the image root
+fn build_script(root: &str, script: &Path, user: Option<&String>) -> Result<(), Error> {
+    let target = format!("{}/tmp/.sow-build.sh", root);
+    sudo(&["install", "-m", "755", script.to_str().unwrap(), &target])?;
+    let mut args = vec!["systemd-nspawn", "-q", "-D", root, "--register=no",
+        "-E", "DEBIAN_FRONTEND=noninteractive"];
+    if let Some(user) = user {
+        args.extend(&["-u", user]);
+    }
+    args.extend(&["/bin/sh", "-ex", "/tmp/.sow-build.sh"]);
+    let result = sudo(&args);
+    sudo(&["rm", "-f", &target])?;
+    result
+}
+
+fn build_root(spec: &Spec, home: &Path, root: &str, dir: &Path, base: Option<&Image>) -> Result<(), Error> {
+    let script = dir.join("step.sh");
+    match base {
+        Some(base) => {
+            sudo(&["mkdir", root])?;
+            sudo(&["tar", "--zstd", "--numeric-owner", "-C", root, "-xpf", base.path().to_str().unwrap()])?;
+            if let Some(mirror) = &spec.mirror {
+                // same suite as the base, packages from the mirror
+                fs::write(&script, format!(
+                    ". /etc/os-release\n\
+                     rm -f /etc/apt/sources.list.d/debian.sources\n\
+                     echo \"deb {} $VERSION_CODENAME main\" > /etc/apt/sources.list\n", mirror))?;
+                build_script(root, &script, None)?;
+            }
+        },
+        None => sudo(&["debootstrap", "--variant=minbase", spec.suite(), root, spec.mirror()])?,
+    }
+    for step in spec.steps.iter() {
+        match step.kind()? {
+            "apt" => {
+                fs::write(&script, format!("apt-get update\napt-get install -y --no-install-recommends {}\n",
+                    step.apt.join(" ")))?;
+                build_script(root, &script, None)?;
+            },
+            "run" => {
+                fs::write(&script, step.run.iter().map(|c| format!("{}\n", c)).collect::<String>())?;
+                build_script(root, &script, step.user.as_ref())?;
+            },
+            "script" => {
+                build_script(root, &home.join(step.script.as_ref().unwrap()), step.user.as_ref())?;
+            },
+            _ => {
+                let to = step.to.as_ref().unwrap();
+                for file in step.copy.iter() {
+                    let source = home.join(file);
+                    let target = match to.ends_with('/') {
+                        true  => format!("{}{}{}", root, to, source.file_name().unwrap().to_str().unwrap()),
+                        false => format!("{}{}", root, to),
+                    };
+                    let mode = match fs::metadata(&source)?.permissions().mode() & 0o111 {
+                        0 => "644",
+                        _ => "755",
+                    };
+                    sudo(&["install", "-D", "-m", mode, source.to_str().unwrap(), &target])?;
+                }
+            },
+        }
+    }
+    fs::write(&script, "apt-get clean\nrm -rf /var/lib/apt/lists/*\n")?;
+    build_script(root, &script, None)
+}
+
+// git commit of the spec, with a + when the checkout has changes
+fn build_commit(home: &Path) -> Option<String> {
+    let git = |args: &[&str]| Command::new("git").arg("-C").arg(home).args(args)
+        .stderr(Stdio::null()).output().ok().filter(|o| o.status.success());
+    let commit = String::from_utf8(git(&["rev-parse", "--short", "HEAD"])?.stdout).ok()?;
+    let dirty = git(&["status", "--porcelain", "."]).map(|o| !o.stdout.is_empty()).unwrap_or(false);
+    Some(format!("{}{}", commit.trim(), if dirty { "+" } else { "" }))
+}
+
+fn build(field: Option<String>, path: PathBuf, version: Option<String>, sign: Option<PathBuf>) {
+    let spec = Spec::load(&path).unwrap();
+    let home = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new(".")).to_path_buf();
+    let base = spec.base.as_ref().map(|b| resolve_image(b, None));
+    let mut image = Image::new(&spec.name, &version.or(spec.version.clone()).unwrap_or_default());
+    if image.version.is_empty() {
+        image.version = image.generate_version();
+    }
+    // before the root is built, not when the image is imported
+    check_image_name(&image);
+    if Image::from_path(&image.path()).is_some() {
+        panic!("Image version {} already exists", &image.version);
+    }
+    let mut metadata = spec.metadata(&image.version);
+    metadata.built = Some(Local::now().format("%Y-%m-%dT%H:%M:%S").to_string());
+    metadata.base = Some(match &base {
+        Some(base) => base.stem(),
+        None       => format!("debian/{}", spec.suite()),
+    });
+    metadata.commit = build_commit(&home);
+    let dir = staging();
+    let rootfs = dir.join(image.file_name());
+    let root = format!("/var/lib/machines/.sow-build-{}", random_pw());
+    println!("Building {} {} in {}", &image.name, &image.version, &root);
+    let result = build_root(&spec, &home, &root, &dir, base.as_ref()).and_then(|_| {
+        let toml = dir.join("image.toml");
+        fs::write(&toml, metadata.to_toml()?)?;
+        sudo(&["install", "-D", "-m", "644", toml.to_str().unwrap(), &format!("{}/{}", &root, METADATA)])?;
+        println!("Running sudo tar --numeric-owner -C {} -cf - .", &root);
+        let mut tar = Command::new("/usr/bin/sudo")
+            .arg("tar")
+            .arg("--numeric-owner")
+            .arg("-C").arg(&root)
+            .arg("-cf").arg("-")
+            .arg(".")
+            .stdout(Stdio::piped())
+            .spawn()?;
+        image::recompress(tar.stdout.take().ok_or("Child process stdout has not been captured.")?, &rootfs)?;
+        match tar.wait()?.success() {
+            true  => Ok(()),
+            false => Err(Error::from("tar failed")),
+        }
+    });
+    if let Err(err) = sudo(&["rm", "-rf", &root]) {
+        eprintln!("Failed to remove {}: {}", &root, err);
+    }
+    if let Err(err) = result {
+        fs::remove_dir_all(&dir).unwrap();
+        panic!("Failed to build {}: {}", &image.name, err);
+    }
+    let signature = sign.map(|key| {
+        let status = Command::new("/usr/bin/ssh-keygen")
+            .arg("-Y").arg("sign")
+            .arg("-n").arg(ssh::IMAGE_NAMESPACE)
+            .arg("-f").arg(&key)
+            .arg(&rootfs)
+            .status().unwrap();
+        if !status.success() {
+            fs::remove_dir_all(&dir).unwrap();
+            panic!("Failed to sign {:?} with {:?}", &rootfs, &key);
+        }
+        PathBuf::from(format!("{}.sig", rootfs.to_str().unwrap()))
+    });
+    import_rootfs(field, rootfs, None, None, signature);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn ls_build_keys(field: Option<String>) {
+    let keys = ssh::build_keys(&Field::select(field).build_keys());
+    print_table(&keys, "build keys", &["PRINCIPAL", "KEY"], |(p, k)| vec![p, k]);
+}
+
+fn add_build_key(field: Option<String>, key: PathBuf, principal: Option<String>) {
+    let field = Field::select(field);
+    let line = match fs::read_to_string(&key) {
+        Ok(k)    => k,
+        Err(err) => panic!("Failed to read build public key {:?}: {}", key, err),
+    };
+    let admin = match AdminKey::parse(&line) {
+        Some(k) if k.options.is_empty() => k,
+        _ => panic!("Not a valid SSH public key: {}", line.trim()),
+    };
+    let principal = principal
+        .or(Some(admin.comment().to_string()).filter(|c| !c.is_empty()))
+        .unwrap_or(key.file_stem().unwrap().to_str().unwrap().to_string());
+    if principal.contains(|c: char| c.is_whitespace() || c == ',') {
+        panic!("Invalid build key principal '{}'", &principal);
+    }
+    let mut keys: Vec<String> = ssh::build_keys(&field.build_keys()).into_iter()
+        .filter(|(p, _)| p != &principal)
+        .map(|(p, k)| format!("{} {}\n", p, k))
+        .collect();
+    keys.push(format!("{}\n", ssh::build_key(&principal, &admin.key)));
+    fs::write(field.build_keys(), keys.concat()).unwrap();
+    field.push("build_keys").unwrap();
+}
+
+fn rm_build_key(field: Option<String>, principal: String) {
+    let field = Field::select(field);
+    let keys = ssh::build_keys(&field.build_keys());
+    let remaining: Vec<String> = keys.iter()
+        .filter(|(p, _)| p != &principal)
+        .map(|(p, k)| format!("{} {}\n", p, k))
+        .collect();
+    if remaining.len() == keys.len() {
+        panic!("Build key '{}' not found in field '{}'", &principal, &field.name);
+    }
+    fs::write(field.build_keys(), remaining.concat()).unwrap();
+    field.push("build_keys").unwrap();
+}
+
+// overlay of the field, or of the Seeds with a label
+fn overlay_path(profile: &Option<String>) -> String {
+    match profile {
+        None => String::from("overlays/field.cpio"),
+        Some(p) if valid_label(p) => {
+            format!("overlays/profiles/{}.cpio", p)
+        },
+        Some(p) => panic!("Invalid profile '{}', use a Seed label", p),
+    }
+}
+
+fn ls_overlays(field: Option<String>) {
+    let field = Field::select(field);
+    let overlays = field.overlays();
+    let seeds = match overlays.iter().any(|o| o.starts_with("overlays/profiles/")) {
+        true  => field.query_seeds().unwrap_or_default(),
+        false => vec![],
+    };
+    let list: Vec<Vec<String>> = overlays.iter().map(|o| {
+        let used_by = match o.strip_prefix("overlays/profiles/").and_then(|p| p.strip_suffix(".cpio")) {
+            Some(profile) => seeds.iter()
+                .filter(|s| s.labels.iter().any(|l| l == profile))
+                .map(|s| s.name.as_str())
+                .collect::<Vec<&str>>().join(","),
+            None => String::from("all"),
+        };
+        vec![o.to_string(), human_size(fs::metadata(field.file(o)).unwrap().len()), used_by]
+    }).collect();
+    print_table(&list, "overlays", &["OVERLAY", "SIZE", "SEEDS"], |r| r.iter().map(|f| f.as_str()).collect());
+}
+
+// pack a directory into a zstd compressed cpio archive owned by root
+fn pack_overlay(dir: &Path, path: &Path) -> Result<(), Error> {
+    let mut rootfs = Rootfs::from_dir(dir)?;
+    // leave the mode of / in the Seed image alone
+    rootfs.entries.retain(|e| e.path != ".");
+    for entry in rootfs.entries.iter_mut() {
+        entry.uid = 0;
+        entry.gid = 0;
+    }
+    let mut zstd = Command::new("zstd").arg("-qf19").arg("-o").arg(path)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    let mut writer = cpio::Writer::new(zstd.stdin.take().ok_or("Child process stdin has not been captured.")?, 0);
+    rootfs.write(&mut writer)?;
+    drop(writer.finish()?);
+    match zstd.wait()?.success() {
+        true  => Ok(()),
+        false => Err(Error::from("zstd failed")),
+    }
+}
+
+fn add_overlay(field: Option<String>, source: PathBuf, profile: Option<String>) {
+    let field = Field::select(field);
+    let overlay = overlay_path(&profile);
+    let path = field.file(&overlay);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let staged = path.with_extension("new");
+    if source.is_dir() {
+        if let Err(err) = pack_overlay(&source, &staged) {
+            fs::remove_file(&staged).ok();
+            panic!("Failed to pack {:?}: {}", &source, err);
+        }
+    } else {
+        let mut magic = [0; 6];
+        File::open(&source).and_then(|mut f| f.read_exact(&mut magic))
+            .unwrap_or_else(|err| panic!("Failed to read {:?}: {}", &source, err));
+        // newc, or compressed with zstd, gzip, or xz
+        if !(magic == *b"070701" || magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd])
+            || magic.starts_with(&[0x1f, 0x8b]) || magic == [0xfd, b'7', b'z', b'X', b'Z', 0]) {
+            panic!("{:?} is not a cpio archive or directory", &source);
+        }
+        fs::copy(&source, &staged).unwrap();
+    }
+    fs::rename(&staged, &path).unwrap();
+    println!("Added {} ({})", &overlay, human_size(fs::metadata(&path).unwrap().len()));
+    field.push(&overlay).unwrap();
+}
+
+fn rm_overlay(field: Option<String>, profile: Option<String>) {
+    let field = Field::select(field);
+    let overlay = overlay_path(&profile);
+    if fs::remove_file(field.file(&overlay)).is_err() {
+        panic!("Overlay {} not found in field '{}'", &overlay, &field.name);
+    }
+    if let Some(sower) = field.sower() {
+        sower.run(&format!("rm -f /var/lib/barley/{}", &overlay)).to_result().unwrap();
+    }
+}
+
+// where to start a new machine
+struct Placement {
+    seed: Option<String>,
+    local: bool,
+    group: Option<String>,
+    policy: String,
+    req: Requirements,
+}
+
+impl Placement {
+    fn from_spec(spec: &MachineSpec) -> Self {
+        Placement {
+            seed: spec.seed.clone(),
+            local: spec.local,
+            group: spec.anti_affinity.clone(),
+            policy: spec.policy().to_string(),
+            req: spec.requirements().unwrap(),
+        }
+    }
+}
+
 struct Machine {
     name: String,
     image: Image,
     field: Field,
     seed: Option<String>,
+    group: Option<String>,
     data: Data,
 }
 
@@ -257,66 +1926,148 @@ impl Machine {
     fn new(
         image: String,
         version: Option<String>,
+        name: Option<String>,
         field: Option<String>,
-        seed: Option<String>,
-        local: bool,
+        placement: Placement,
+        volumes: &[Volume],
     ) -> Machine {
-        if !local && seed.is_none() {
-            // TODO: pick a seed with a scheduler
-            panic!("Unable to pick a Seed for this machine, use --seed <host> or --local.");
-        }
-        let image = match version {
-            Some(v) => Image::new(&image, &v),
-            None    => Image::latest(&image).expect(&format!(
-                "No images found for '{}'. Run 'sow import <path>'.",
-                image,
-            )),
+        let image = Image::resolve(&image, version).unwrap_or_else(|| panic!(
+            "No images found for '{}'. Run 'sow import <path>'.",
+            image,
+        ));
+        let field = Field::select(field);
+        let Placement { seed, local, group, policy, req } = placement;
+        let seed = match (local, seed) {
+            (false, None) => {
+                // volumes declared by the image unless given, same as start
+                let disk = match volumes.is_empty() {
+                    true  => image.metadata().unwrap_or_default().volumes.iter().map(|v| v.bytes()).sum(),
+                    false => volumes.iter().map(|v| v.bytes()).sum(),
+                };
+                let req = Requirements {
+                    disk,
+                    avoid: group.as_ref().map(|g| field.group_seeds(g)).unwrap_or_default(),
+                    ..req
+                };
+                Some(field.schedule(&policy, &req).expect(
+                    "Unable to pick a Seed for this machine, use --seed <host> or --local."))
+            },
+            (true, _) if !req.labels.is_empty() => panic!("--require does not apply to --local machines"),
+            (true, seed) => seed,
+            (false, Some(seed)) => {
+                // a Seed given by hand still has to meet --require and --anti-affinity
+                if !req.labels.is_empty() || group.is_some() {
+                    let seeds = field.query_seeds().unwrap_or_else(|err| panic!(
+                        "Unable to check Seed {} against --require and --anti-affinity: {}", &seed, err));
+                    let info = seeds.iter().find(|s| s.name == seed).unwrap_or_else(|| panic!(
+                        "Seed {} has not registered, unable to check --require and --anti-affinity", &seed));
+                    let req = Requirements {
+                        avoid: group.as_ref().map(|g| field.group_seeds(g)).unwrap_or_default(),
+                        ..req
+                    };
+                    if let Err(err) = req.check(info) {
+                        panic!("{}", err);
+                    }
+                }
+                Some(seed)
+            },
         };
-        let field = match field {
-            Some(f) => Field::new(&f),
-            None    => Field::latest().expect("No Barley fields found. Run 'sow new <name>'."),
+        if let Some(seed) = &seed {
+            if !field.seeds().iter().any(|s| &s.name == seed) {
+                // new Seed, look up its IP address for ssh_config
+                if let Err(err) = field.refresh_seeds() {
+                    eprintln!("Failed to refresh Seeds of field '{}': {}", &field.name, err);
+                }
+                write_ssh_config();
+            }
+        }
+        if let Some(name) = &name {
+            if !Regex::new(r"^[[:alnum:]][[:alnum:]_.-]*$").unwrap().is_match(name) {
+                panic!("Invalid machine name '{}', use letters, digits, and _.-", name);
+            }
+        }
+        let name = match name {
+            Some(name) => match fs::create_dir(field.file(&name)) {
+                Ok(_)  => name,
+                Err(err) => panic!(
+                    "Failed to create data directory for machine '{}' in field '{}': {}",
+                    &name, &field.name, err,
+                ),
+            },
+            None => field.data().reserve(&image.name).unwrap_or_else(|_| panic!(
+                "Failed to create data directory for machine '{}', does field '{}' exist?",
+                &image.name, &field.name
+            )),
         };
-        let name = image.name.to_string();  // TODO: add NameCounter
-        let data = Data::new(field.file(&name)).expect(&format!(
-            "Failed to create data directory for machine '{}', does field '{}' exist?",
-            &name, &field.name
-        ));
-        Machine { name, image, field, seed, data }
+        let data = Data::new(field.file(&name)).unwrap();
+        Machine { name, image, field, seed, group, data }
     }
 
-    fn command(&self, script: &str) -> Command {
-        match &self.seed {
-            Some(seed) => {
-                println!("Running ssh {} '{}'", &seed, &script);
-                let mut c = Command::new("/usr/bin/ssh");
-                c.arg(&seed).arg(script);
-                c
-            },
-            None => {
-                println!("Running sudo sh -c '{}'", &script);
-                let mut c = Command::new("/usr/bin/sudo");
-                c.arg("/bin/sh").arg("-c").arg(script);
-                c
-            },
+    fn load(field: &Field, name: &str) -> Option<Machine> {
+        if !field.file(name).is_dir() {
+            return None;
         }
+        let data = Data::new(field.file(name)).ok()?;
+        let image = Image::parse(data.read("image").ok()?.trim());
+        let seed = data.read("seed").ok().filter(|s| !s.is_empty());
+        let group = data.read("group").ok().filter(|g| !g.is_empty());
+        Some(Machine { name: name.to_string(), image, field: field.clone(), seed, group, data })
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        self.data.write("image", &self.image.stem())?;
+        self.data.write("seed", self.seed.as_deref().unwrap_or(""))?;
+        self.data.write("group", self.group.as_deref().unwrap_or(""))
+    }
+
+    fn command(&self, script: &str) -> Command {
+        self.field.command(&self.seed, script)
     }
 
     fn nspawn(&self, script: &str) -> Command {
         self.command(&format!("systemd-nspawn -M {} -UPq sh -c '{}'", self.name, script))
     }
 
-    fn install(&self, from: &PathBuf, to: &str, mode: &str) -> Result<(), Error> {
+    fn install_script(to: &str, mode: &str) -> String {
         let to = format!("/var/lib/barley/{}", to);
-        self.nspawn(&format!("install -m {} -o barley -g barley /dev/null {} && \
-                              cat > {}", mode, to, to))
+        format!("install -D -m {} -o barley -g barley /dev/null {} && cat > {}", mode, to, to)
+    }
+
+    fn install(&self, from: &PathBuf, to: &str, mode: &str) -> Result<(), Error> {
+        self.nspawn(&Self::install_script(to, mode))
             .stdin(Stdio::from(File::open(from)?))
             .to_result()
     }
 
+    // same as install, but for a machine that is already running
+    fn update(&self, from: &PathBuf, to: &str, mode: &str) -> Result<(), Error> {
+        self.run(&Self::install_script(to, mode))
+            .stdin(Stdio::from(File::open(from)?))
+            .to_result()
+    }
+
+    fn write(&self, data: &[u8], to: &str, mode: &str) -> Result<(), Error> {
+        let mut child = self.run(&Self::install_script(to, mode)).stdin(Stdio::piped()).spawn()?;
+        child.stdin.take().ok_or("Child process stdin has not been captured.")?.write_all(data)?;
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(Error::CommandError(format!("Failed to write {}: {:?}", to, status)));
+        }
+        Ok(())
+    }
+
     fn run(&self, script: &str) -> Command {
         self.command(&format!("systemd-run -M {} -Pq --wait sh -c '{}'", self.name, script))
     }
 
+    fn output(&self, script: &str) -> Result<Vec<u8>, Error> {
+        let output = self.run(script).stderr(Stdio::inherit()).output()?;
+        if !output.status.success() {
+            return Err(Error::CommandError(format!("{} failed: {:?}", script, output.status)));
+        }
+        Ok(output.stdout)
+    }
+
     fn download(&self, name: &str) -> Result<(), Error> {
         self.run(&format!("cat /var/lib/barley/{}", name))
             .stdout(Stdio::from(File::create(self.data.file(name))?))
@@ -334,27 +2085,104 @@ impl Machine {
     }
 
     fn import(&self) -> Result<(), Error> {
-        self.command(&format!("zstdcat | machinectl -q import-tar - {}", self.name))
-            .stdin(Stdio::from(File::open(&self.image.path())?))
-            .to_result()
+        self.image.check()?;
+        self.field.verify(&self.image.path(), self.image.signature().as_ref())?;
+        if self.seed.is_some() && self.field.sower().is_some() {
+            self.field.push_image(&self.image)?;
+            return self.command(&format!("barley-pull {} {} {}",
+                &self.image.name, &self.image.version, self.name)).to_result();
+        }
+        let sha256 = self.image.sha256()?;
+        let template = self.image.template()?;
+        let meta = format!("/var/lib/barley/images/{}", self.image.stem());
+        let present = self.command(&format!(
+            "grep -qx {} {} && machinectl show-image {} >/dev/null 2>&1",
+            &sha256, &meta, &template)).to_result().is_ok();
+        if !present {
+            // check the image on the target before importing it, the same
+            // way barley-pull does, signature included
+            let mut cleanup = String::new();
+            let mut check = String::new();
+            if let (Some(_), Some(signature), true) = (&self.seed, self.image.signature(), self.field.trusted()) {
+                let sig = format!("{}.sig", &meta);
+                let signers = format!("{}.signers", &meta);
+                for (from, to) in &[(signature, &sig), (self.field.build_keys(), &signers)] {
+                    self.command(&format!("mkdir -p /var/lib/barley/images && cat > {}", to))
+                        .stdin(Stdio::from(File::open(from)?))
+                        .to_result()?;
+                }
+                cleanup = format!(" {} {}", &sig, &signers);
+                check = format!(
+                    " && p=$(ssh-keygen -Y find-principals -f {} -s {}) && \
+                     ssh-keygen -q -Y verify -f {} -I \"$p\" -n barley-image -s {} < $f >/dev/null",
+                    &signers, &sig, &signers, &sig);
+            }
+            self.command(&format!(
+                "trap \"rm -f{}\" EXIT; machinectl show-image {} >/dev/null 2>&1 || {{ \
+                   f=$(mktemp) && trap \"rm -f $f{}\" EXIT && cat > $f && \
+                   echo \"{}  $f\" | sha256sum -c --status{} && \
+                   zstdcat $f | machinectl -q import-tar - {}; }}",
+                &cleanup, &template, &cleanup, &sha256, &check, &template))
+                .stdin(Stdio::from(File::open(self.image.path())?))
+                .to_result()?;
+            self.command(&format!("mkdir -p /var/lib/barley/images && echo {} > {}", &sha256, &meta))
+                .to_result()?;
+        }
+        self.command(&format!("machinectl clone {} {}", &template, self.name)).to_result()
     }
 
-    fn install_ca(&self) -> Result<(), Error> {
+    fn install_ca(&self, pw: Option<&str>) -> Result<(), Error> {
         let key = self.data.file("machine.key");
         let cert = self.data.file("machine.crt");
         tls::generate(&key)?;
-        println!("When prompted, enter root.key password for {}", &self.field.name);
+        if pw.is_none() {
+            println!("When prompted, enter root.key password for {}", &self.field.name);
+        }
         tls::sign_ca(
             &self.name,
             &self.field.cacert(),
             &self.field.cakey(),
+            pw,
             &key,
             &cert,
         )?;
         self.install(&key, "machine.key", "600")?;
         self.install(&cert, "machine.crt", "644")?;
-        self.install(&self.field.cacert(), "root.crt", "644")?;
+        self.install(&self.field.bundle(), "root.crt", "644")?;
+        if fs::metadata(self.field.crosscert()).is_ok() {
+            self.install(&self.field.crosscert(), "cross.crt", "644")?;
+        }
         self.install(&self.field.admin(), "admin.pub", "644")?;
+        if fs::metadata(self.field.build_keys()).is_ok() {
+            self.install(&self.field.build_keys(), "build_keys", "644")?;
+        }
+        if fs::metadata(self.field.krl()).is_ok() {
+            self.install(&self.field.krl(), "revoked.krl", "644")?;
+        }
+        for overlay in self.field.overlays() {
+            self.install(&self.field.file(&overlay), &overlay, "644")?;
+        }
+        Ok(())
+    }
+
+    fn has_ca(&self) -> bool {
+        self.data.file("machine.key").is_file()
+    }
+
+    fn reissue_ca(&self, pw: &str) -> Result<(), Error> {
+        let key = self.data.file("machine.key");
+        let cert = self.data.file("machine.crt");
+        tls::sign_ca(
+            &self.name,
+            &self.field.cacert(),
+            &self.field.cakey(),
+            Some(pw),
+            &key,
+            &cert,
+        )?;
+        self.update(&cert, "machine.crt", "644")?;
+        self.update(&self.field.bundle(), "root.crt", "644")?;
+        self.update(&self.field.crosscert(), "cross.crt", "644")?;
         Ok(())
     }
 
@@ -378,13 +2206,15 @@ impl Machine {
         Ok(())
     }
 
-    fn wait_for(&self, test: &str) -> Result<(), Error> {
+    // poll quickly at first, then once a second until timeout seconds are up
+    fn wait_for(&self, test: &str, timeout: u64) -> Result<(), Error> {
         self.command(&format!(
-            "d=.01; \
-             for i in $(seq 0 8); do \
-               sleep $d; d=$(echo $d*2|bc); \
-               {} && break; \
-             done", test))
+            "end=$(($(date +%s) + {})); \
+             for d in .01 .02 .04 .08 .16 .32 .64; do sleep $d; {} && exit 0; done; \
+             until {}; do \
+               [ $(date +%s) -lt $end ] || exit 1; \
+               sleep 1; \
+             done", timeout, test, test))
             .to_result()
     }
 
@@ -392,44 +2222,389 @@ impl Machine {
         self.wait_for(&format!(
             "machinectl show --property=State --value {} | grep -q running",
             self.name,
-        ))?;
-        self.wait_for(&format!("systemd-run -M {} -Pq --wait true", self.name))
+        ), START_TIMEOUT)?;
+        self.wait_for(&format!("systemd-run -M {} -Pq --wait true", self.name), START_TIMEOUT)
     }
 
-    fn get_ssh_ca(&self) -> Result<String, Error> {
-        self.download("ca.pub")?;
-        self.data.read("ca.pub")
+    fn wait_for_exit(&self) -> Result<(), Error> {
+        self.wait_for(&format!("! machinectl show {} >/dev/null 2>&1", self.name), STOP_TIMEOUT)
     }
 
-    fn update_known_hosts(&self, key: &str) -> Result<(), Error> {
+    fn get_ssh_ca(&self) -> Result<(), Error> {
+        self.download("ca.pub")?;
+        let key = self.data.read("ca.pub")?;
         if !key.starts_with("ssh-ed25519 ") {
+            fs::remove_file(self.data.file("ca.pub"))?;
             return Err(Error::from(format!("Invalid key: {}", key)));
         }
-        OpenOptions::new()
-            .append(true)
-            .open(home_ssh().join("known_hosts"))?
-            .write_all(
-                format!("@cert-authority * {}-{}\n", key.trim(), self.image.version)
-                .as_bytes())?;
         Ok(())
     }
 
-    fn start(&self, ca: bool, network: Option<Vec<String>>) -> Result<(), Error> {
+    fn update_known_hosts(&self) -> Result<(), Error> {
+        self.field.write_known_hosts()?;
+        write_ssh_config();
+        Ok(())
+    }
+
+    fn network(&self) -> Option<Vec<String>> {
+        self.data.read("network").ok().map(|n| n.lines().map(|l| l.to_string()).collect())
+    }
+
+    fn volumes(&self) -> Vec<Volume> {
+        self.data.read("volumes").unwrap_or_default().lines()
+            .filter_map(|v| Volume::parse(v).ok())
+            .collect()
+    }
+
+    // Let Sower start the machine again when its Seed reboots. Machines with
+    // a CA key are left out, their keys can only be installed by sow.
+    fn assign(&self, stopped: bool) -> Result<(), Error> {
+        let seed = match &self.seed {
+            Some(seed) if !self.has_ca() => seed,
+            _ => return Ok(()),
+        };
+        let sower = match self.field.sower() {
+            Some(sower) => sower,
+            None => {
+                eprintln!("Sower machine not found in field '{}', machine '{}' \
+                           will not be restarted after Seed reboot.", &self.field.name, &self.name);
+                return Ok(());
+            },
+        };
+        self.field.push_image(&self.image)?;
+        let assignment = Assignment {
+            name: self.name.to_string(),
+            image: self.image.stem(),
+            network: self.network(),
+            volumes: self.volumes().iter().map(|v| v.to_string()).collect(),
+            stopped,
+        };
+        self.data.write("assignment.json", &serde_json::to_string(&assignment)
+            .map_err(|err| Error::DataError(err.to_string()))?)?;
+        sower.run(&format!("barley assign {}", seed))
+            .stdin(Stdio::from(File::open(self.data.file("assignment.json"))?))
+            .to_result()
+    }
+
+    fn unassign(&self) -> Result<(), Error> {
+        match (&self.seed, self.field.sower()) {
+            (Some(seed), Some(sower)) => {
+                sower.run(&format!("barley unassign {} {}", seed, &self.name)).to_result()
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn stop(&self) -> Result<(), Error> {
+        self.assign(true)?;
+        self.command(&format!("machinectl stop {}", self.name)).to_result()?;
+        self.wait_for_exit()
+    }
+
+    // machines that are not running, e.g. --ca machines after their Seed
+    // rebooted, are started again with their saved network and volumes
+    fn restart(&self, pw: Option<String>) -> Result<(), Error> {
+        if !self.is_running() {
+            return self.resume(self.has_ca(), pw, self.network(), &self.volumes());
+        }
+        self.command(&format!("machinectl reboot {}", self.name)).to_result()?;
+        self.wait_for_machine()
+    }
+
+    fn is_running(&self) -> bool {
+        self.command(&format!("machinectl show {} >/dev/null 2>&1", self.name))
+            .to_result().is_ok()
+    }
+
+    fn has_image(&self) -> bool {
+        self.command(&format!("machinectl show-image {} >/dev/null 2>&1", self.name))
+            .to_result().is_ok()
+    }
+
+    // terminate the machine and remove its image from the Seed, keep the data
+    fn remove_image(&self) -> Result<(), Error> {
+        self.command(&format!(
+            "if machinectl show {} >/dev/null 2>&1; then machinectl terminate {}; fi",
+            self.name, self.name)).to_result()?;
+        self.wait_for_exit()?;
+        self.command(&format!(
+            "machinectl remove {} && rm -f /etc/systemd/nspawn/{}.nspawn",
+            self.name, self.name)).to_result()
+    }
+
+    fn remove(&self) -> Result<(), Error> {
+        self.unassign()?;
+        self.remove_image()?;
+        fs::remove_dir_all(self.field.file(&self.name))?;
+        self.field.write_known_hosts()?;
+        Ok(())
+    }
+
+    fn save_config(&self, network: &Option<Vec<String>>, volumes: &[Volume]) -> Result<(), Error> {
+        match network {
+            Some(n) => self.data.write("network", &n.iter().map(|l| format!("{}\n", l)).collect::<String>())?,
+            None    => fs::remove_file(self.data.file("network")).or(Ok::<(), Error>(()))?,
+        }
+        self.data.write("volumes", &volumes.iter().map(|v| format!("{}\n", v)).collect::<String>())
+    }
+
+    fn attach(&self, volumes: &[Volume]) -> Result<(), Error> {
+        for volume in volumes {
+            self.command(&volume.script(&self.name)).to_result()?;
+        }
+        Ok(())
+    }
+
+    fn start(
+        &self,
+        ca: bool,
+        pw: Option<String>,
+        network: Option<Vec<String>>,
+        volumes: &[Volume],
+    ) -> Result<(), Error> {
+        // volumes and network mode declared by the image unless given
+        let metadata = self.image.metadata().unwrap_or_default();
+        let network = match network {
+            Some(n) => Some(n),
+            None    => metadata.network_config()?,
+        };
+        let volumes = match volumes.is_empty() {
+            true  => &metadata.volumes[..],
+            false => volumes,
+        };
         self.check_network(&network)?;
         self.import()?;
+        self.save()?;
+        self.save_config(&network, volumes)?;
         if ca {
-            self.install_ca()?;
+            self.install_ca(pw.as_deref())?;
+        } else {
+            fs::remove_file(self.data.file("machine.key")).ok();
+            fs::remove_file(self.data.file("machine.crt")).ok();
         }
         self.write_config(network)?;
         self.command(&format!("machinectl start {}", self.name)).to_result()?;
-        if ca {
+        if ca || !volumes.is_empty() {
             self.wait_for_machine()?;
-            self.update_known_hosts(&self.get_ssh_ca()?)?;
         }
-        Ok(())
+        // attach-disk shifts volume ownership into the user namespace of
+        // the running machine
+        self.attach(volumes)?;
+        if ca {
+            self.get_ssh_ca()?;
+            self.update_known_hosts()?;
+        }
+        self.assign(false)
+    }
+
+    // start a new machine, and leave no trace of it when that fails
+    fn create(
+        &self,
+        ca: bool,
+        pw: Option<String>,
+        network: Option<Vec<String>>,
+        volumes: &[Volume],
+    ) -> Result<(), Error> {
+        let result = self.start(ca, pw, network, volumes);
+        if result.is_err() {
+            self.unassign().ok();
+            if self.has_image() {
+                self.remove_image().ok();
+            }
+            fs::remove_dir_all(self.field.file(&self.name)).ok();
+        }
+        result
+    }
+
+    // start a stopped machine, or start it over if its Seed has lost the image
+    fn resume(
+        &self,
+        ca: bool,
+        pw: Option<String>,
+        network: Option<Vec<String>>,
+        volumes: &[Volume],
+    ) -> Result<(), Error> {
+        if !self.has_image() {
+            return self.start(ca, pw, network, volumes);
+        }
+        self.command(&format!("machinectl start {}", self.name)).to_result()?;
+        self.wait_for_machine()?;
+        self.assign(false)
+    }
+
+    // previous image version is kept for rollback
+    fn replace(
+        &mut self,
+        image: Image,
+        ca: bool,
+        pw: Option<String>,
+        network: Option<Vec<String>>,
+        volumes: &[Volume],
+    ) -> Result<(), Error> {
+        if Image::from_path(&image.path()).is_none() {
+            return Err(Error::from(format!("Image {} {} not found", &image.name, &image.version)));
+        }
+        // keep Sower from starting the old image while it is being replaced
+        self.assign(true)?;
+        self.remove_image()?;
+        // a new network or CA for the same image keeps the previous version
+        if image.stem() != self.image.stem() {
+            self.data.write("previous", &self.image.stem())?;
+        }
+        self.image = image;
+        self.start(ca, pw, network, volumes)
+    }
+
+    fn previous(&self) -> Option<Image> {
+        self.data.read("previous").ok().map(|p| Image::parse(p.trim()))
+    }
+
+    // same network and volumes, new image
+    fn upgrade(&mut self, image: Image, pw: Option<String>, ready: &str) -> Result<(), Error> {
+        let network = self.network();
+        let volumes = self.volumes();
+        println!("Upgrading {} from {} {} to {}",
+            &self.name, &self.image.name, &self.image.version, &image.version);
+        self.replace(image, self.has_ca(), pw, network, &volumes)?;
+        self.wait_for_machine()?;
+        self.run(ready).to_result()
+            .map_err(|err| Error::from(format!(
+                "Machine '{}' is not ready, run 'sow rollback {}': {}", &self.name, &self.name, err)))
+    }
+}
+
+fn upgrade(
+    field: Option<String>,
+    pass_fd: Option<i32>,
+    target: String,
+    version: Option<String>,
+    ready: String,
+) {
+    let field = Field::select(field);
+    let machines: Vec<Machine> = match Machine::load(&field, &target) {
+        Some(machine) => vec![machine],
+        None => field.machines().into_iter().filter(|m| m.image.name == target).collect(),
+    };
+    if machines.is_empty() {
+        panic!("No machine or image '{}' found in field '{}'", &target, &field.name);
+    }
+    let mut pw = None;
+    for mut machine in machines {
+        let image = match &version {
+            Some(v) => Image::resolve(&machine.image.name, Some(v.to_string())).unwrap(),
+            None => match Image::latest(&machine.image.name) {
+                Some(i) if Image::compare_versions(&i.version, &machine.image.version)
+                    == Ordering::Greater => i,
+                _ => {
+                    println!("Machine '{}' is up to date.", &machine.name);
+                    continue;
+                },
+            },
+        };
+        if image.version == machine.image.version {
+            println!("Machine '{}' is up to date.", &machine.name);
+            continue;
+        }
+        if machine.has_ca() && pw.is_none() {
+            pw = field.password(pass_fd);
+        }
+        machine.upgrade(image, pw.clone(), &ready).unwrap();
     }
 }
 
+fn rollback(field: Option<String>, pass_fd: Option<i32>, name: String, ready: String) {
+    let field = Field::select(field);
+    let mut machine = field.machine(&name);
+    let image = machine.previous().unwrap_or_else(|| panic!(
+        "Machine '{}' has no previous version to roll back to", &name));
+    let pw = if machine.has_ca() { field.password(pass_fd) } else { None };
+    machine.upgrade(image, pw, &ready).unwrap();
+}
+
+fn apply(field: Option<String>, pass_fd: Option<i32>, path: PathBuf, dry_run: bool) {
+    let field = Field::select(field);
+    let mut manifest = Manifest::load(&path).unwrap();
+    // pin tagged images to the version the tag points to now
+    for spec in manifest.machines.values_mut() {
+        if spec.image.contains(':') || spec.version.is_some() {
+            let image = Image::resolve(&spec.image, spec.version.clone()).unwrap();
+            spec.image = image.name;
+            spec.version = Some(image.version);
+        }
+    }
+    let actions = manifest::plan(&manifest, &field.current(), |name| {
+        Image::latest(name).map(|i| i.version)
+    }).unwrap();
+    if actions.is_empty() {
+        println!("Field '{}' is up to date.", &field.name);
+        return;
+    }
+    for action in actions.iter() {
+        println!("{}", action);
+    }
+    if dry_run {
+        return;
+    }
+    let needs_ca = actions.iter().any(|a| match a {
+        Action::Stop { .. } => false,
+        _ => manifest.machines[a.name()].ca,
+    });
+    let pw = if needs_ca { field.password(pass_fd) } else { None };
+    for action in actions {
+        let spec = &manifest.machines[action.name()];
+        let volumes = spec.volumes().unwrap();
+        let result = match &action {
+            Action::Create { name, version, .. } => {
+                Machine::new(
+                    spec.image.to_string(),
+                    Some(version.to_string()),
+                    Some(name.to_string()),
+                    Some(field.name.to_string()),
+                    Placement::from_spec(spec),
+                    &volumes,
+                ).create(spec.ca, pw.clone(), spec.network.clone(), &volumes)
+            },
+            Action::Start { name } => {
+                field.machine(name).resume(spec.ca, pw.clone(), spec.network.clone(), &volumes)
+            },
+            Action::Stop { name } => { field.machine(name).stop() },
+            Action::Upgrade { name, image, to, .. } => {
+                let mut machine = field.machine(name);
+                machine.group = spec.anti_affinity.clone();
+                machine.replace(Image::new(image, to), spec.ca, pw.clone(), spec.network.clone(), &volumes)
+            },
+            Action::Replace { name, .. } => {
+                let mut machine = field.machine(name);
+                let image = Image::new(&machine.image.name, &machine.image.version);
+                machine.group = spec.anti_affinity.clone();
+                machine.replace(image, spec.ca, pw.clone(), spec.network.clone(), &volumes)
+            },
+            Action::Move { name, .. } => {
+                let machine = field.machine(name);
+                machine.remove().and_then(|_| Machine::new(
+                    spec.image.to_string(),
+                    Some(machine.image.version.to_string()),
+                    Some(name.to_string()),
+                    Some(field.name.to_string()),
+                    Placement::from_spec(spec),
+                    &volumes,
+                ).create(spec.ca, pw.clone(), spec.network.clone(), &volumes))
+            },
+        };
+        if let Err(err) = result {
+            panic!("Failed to {}: {}", action, err);
+        }
+    }
+}
+
+// seconds to wait for a machine to come up, and for its services to stop,
+// the latter is the default stop timeout of systemd units
+const START_TIMEOUT: u64 = 30;
+const STOP_TIMEOUT: u64 = 90;
+
+// wait for the machine to finish booting, failed units are not fatal
+const READY: &str = "systemctl is-system-running --wait >/dev/null; systemctl is-active -q multi-user.target";
+
 /// Ephemeral bare-metal provisioning system
 #[derive(StructOpt)]
 struct Opt {
@@ -437,6 +2612,10 @@ struct Opt {
     #[structopt(short, long, env = "BARLEY_FIELD")]
     field: Option<String>,
 
+    /// Read root.key password from this file descriptor
+    #[structopt(long, env = "BARLEY_PASS_FD")]
+    pass_fd: Option<i32>,
+
     #[structopt(subcommand)]
     op: Option<Op>
 }
@@ -454,31 +2633,218 @@ enum Op {
         /// default: ~/.ssh/id_ed25519.pub
         #[structopt(short, long)]
         key: Option<PathBuf>,
+        /// Store root.key password in the Secret Service keyring
+        #[structopt(long)]
+        keyring: bool,
+        /// Seal root.key password in an age envelope that can be opened
+        /// with this SSH key loaded into ssh-agent
+        #[structopt(long)]
+        envelope: Option<PathBuf>,
     },
 
-    /// List imported images
-    Images,
+    /// Manage the Barley field
+    Field {
+        #[structopt(subcommand)]
+        op: FieldOp,
+    },
+
+    /// Manage SSH keys that are granted root access to Seeds
+    Admins {
+        #[structopt(subcommand)]
+        op: Option<AdminsOp>,
+    },
+
+    /// Manage SSH keys that are trusted to sign images
+    BuildKeys {
+        #[structopt(subcommand)]
+        op: Option<BuildKeysOp>,
+    },
+
+    /// Manage initramfs overlays that Seeds boot with on top of the Seed image
+    Overlays {
+        #[structopt(subcommand)]
+        op: Option<OverlaysOp>,
+    },
+
+    /// Issue short-lived SSH certificates signed by a field admin key
+    SshCert {
+        #[structopt(subcommand)]
+        op: SshCertOp,
+    },
+
+    /// Regenerate SSH client config for the field from Seeds registered with Sower
+    SshConfig,
+
+    /// Manage imported images
+    Images {
+        #[structopt(subcommand)]
+        op: Option<ImagesOp>,
+    },
+
+    /// Point a tag like stable or testing at an image version
+    Tag {
+        /// Image name
+        image: String,
+        /// Image version
+        version: String,
+        /// Tag name
+        tag: String,
+    },
+
+    /// List image tags
+    Tags {
+        /// Image name, default: all images
+        image: Option<String>,
+        /// Show every move of the tags
+        #[structopt(long)]
+        history: bool,
+    },
+
+    /// List Seeds registered with Sower and their status
+    Seeds,
+
+    /// Add or remove Seed labels used for machine placement
+    Label {
+        /// Seed name
+        seed: String,
+        /// Labels to be added
+        labels: Vec<String>,
+        /// Labels to be removed
+        #[structopt(long, number_of_values = 1)]
+        rm: Vec<String>,
+    },
+
+    /// List machines and their state on all Seeds
+    Machines,
+
+    /// Stop a running machine
+    Stop {
+        /// Machine name
+        name: String,
+    },
+
+    /// Restart a running machine, or start it again when it is not running
+    Restart {
+        /// Machine name
+        name: String,
+    },
+
+    /// Stop and remove a machine, its image, and its data in the field
+    Rm {
+        /// Machine name
+        name: String,
+    },
+
+    /// Upgrade a machine, or all machines of an image one at a time
+    Upgrade {
+        /// Machine or image name
+        target: String,
+        /// Image version or tag, default: latest version
+        #[structopt(short, long)]
+        version: Option<String>,
+        /// Script that has to succeed inside the upgraded machine before
+        /// moving on to the next one
+        #[structopt(long, default_value = READY)]
+        ready: String,
+    },
+
+    /// Restore the previous image version of a machine
+    Rollback {
+        /// Machine name
+        name: String,
+        /// Script that has to succeed inside the machine after rollback
+        #[structopt(long, default_value = READY)]
+        ready: String,
+    },
+
+    /// Upload an image to Sower for Seeds to pull
+    Push {
+        /// Image name or name:tag
+        image: String,
+        /// Image version or tag, default: latest version
+        #[structopt(short, long)]
+        version: Option<String>,
+    },
+
+    /// Start, stop, and upgrade machines to match a manifest
+    Apply {
+        /// Manifest file (TOML)
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Print the plan without changing anything
+        #[structopt(long)]
+        dry_run: bool,
+    },
 
     /// Import an image
     Import {
-        /// Path to the image file (name.tar.zst or name_version.tar.zst)
+        /// Image file (name.tar.zst or name_version.tar.zst, also .tar, .tar.gz,
+        /// or .tar.xz), rootfs directory, OCI image layout directory, docker
+        /// save archive, HTTP URL, or - for stdin
+        source: String,
+        /// Image name, default: from the image metadata or the file name
+        #[structopt(short, long)]
+        name: Option<String>,
+        /// Image version, default: from the image metadata or the file name,
+        /// or today's date
+        #[structopt(short, long)]
+        version: Option<String>,
+        /// Signature made with ssh-keygen -Y sign -n barley-image, default: <path>.sig
+        #[structopt(short, long, parse(from_os_str))]
+        signature: Option<PathBuf>,
+    },
+
+    /// Build an image from a spec with debootstrap and systemd-nspawn, and import it
+    Build {
+        /// Build spec (TOML), files and scripts are relative to it
         #[structopt(parse(from_os_str))]
-        path: PathBuf,
+        spec: PathBuf,
+        /// Image version, default: from the spec or today's date
+        #[structopt(short, long)]
+        version: Option<String>,
+        /// Build private key to sign the image with
+        #[structopt(short, long, parse(from_os_str))]
+        sign: Option<PathBuf>,
     },
 
     /// Start a new machine from an imported image
     Start {
-        /// Image name
+        /// Image name or name:tag
         image: String,
 
-        /// Image version, default: latest version
+        /// Image version or tag, default: latest version
         #[structopt(short, long)]
         version: Option<String>,
 
-        /// Specify the Seed host to start machines on
-        #[structopt(short, long, required_if("local", "false"))]
+        /// Machine name, default: image name with a counter (e.g. postgres-1)
+        #[structopt(long)]
+        name: Option<String>,
+
+        /// Specify the Seed host to start machines on, default: pick one with
+        /// the scheduling policy
+        #[structopt(short, long)]
         seed: Option<String>,
 
+        /// Scheduling policy: least-loaded, spread, or bin-pack
+        #[structopt(long, default_value = "least-loaded")]
+        policy: String,
+
+        /// Free memory required on the Seed
+        #[structopt(long, default_value = "512M", parse(try_from_str = parse_size))]
+        memory: u64,
+
+        /// Only start on a Seed with this label
+        #[structopt(long, number_of_values = 1)]
+        require: Vec<String>,
+
+        /// Prefer Seeds with this label
+        #[structopt(long, number_of_values = 1)]
+        prefer: Vec<String>,
+
+        /// Never start on the same Seed with another machine from this group
+        #[structopt(long)]
+        anti_affinity: Option<String>,
+
         /// Start machines locally rather than on a remote Seed host
         #[structopt(long, conflicts_with("seed"))]
         local: bool,
@@ -490,6 +2856,202 @@ enum Op {
         /// Network options for systemd.nspawn(5), default: Bridge=br0
         #[structopt(short, long, number_of_values = 1)]
         network: Option<Vec<String>>,
+
+        /// Persistent volume, path:owner[:size] (e.g. /var/lib/postgresql:postgres:32G)
+        #[structopt(long, number_of_values = 1, parse(try_from_str = Volume::parse))]
+        volume: Vec<Volume>,
+    },
+}
+
+#[derive(StructOpt)]
+enum AdminsOp {
+    /// List admin keys
+    Ls,
+
+    /// Add an admin key
+    Add {
+        /// SSH public key file
+        #[structopt(parse(from_os_str))]
+        key: PathBuf,
+        /// Comma-separated principals accepted in certificates signed by this key
+        #[structopt(short, long)]
+        principals: Option<String>,
+        /// Expiry time of the key, YYYYMMDD[HHMM[SS]]
+        #[structopt(short, long)]
+        expiry: Option<String>,
+    },
+
+    /// Remove an admin key
+    Rm {
+        /// Comment of the admin key to be removed
+        comment: String,
+    },
+}
+
+#[derive(StructOpt)]
+enum ImagesOp {
+    /// List imported images
+    Ls,
+
+    /// Show image metadata and the Debian packages it contains
+    Show {
+        /// Image name or name:tag
+        image: String,
+        /// Image version or tag, default: latest version
+        version: Option<String>,
+        /// Print metadata and packages as JSON
+        #[structopt(long)]
+        json: bool,
+    },
+
+    /// Compare Debian packages and files of two versions of an image
+    Diff {
+        /// Image name
+        image: String,
+        /// Old version or tag
+        from: String,
+        /// New version or tag
+        to: String,
+    },
+
+    /// List open Debian security issues in the latest image versions and the
+    /// versions machines use, from a local security tracker snapshot
+    Audit {
+        /// Image name, default: all images
+        image: Option<String>,
+        /// Security tracker JSON, default: ~/.barley/debian-security.json
+        #[structopt(long, parse(from_os_str))]
+        tracker: Option<PathBuf>,
+        /// Debian release codename, default: from os-release of the image
+        #[structopt(long)]
+        release: Option<String>,
+        /// Include unimportant and end-of-life issues
+        #[structopt(long)]
+        all: bool,
+    },
+
+    /// Remove an image version, or all versions of an image that are not in use
+    Rm {
+        /// Image name
+        image: String,
+        /// Image version, default: all versions
+        version: Option<String>,
+    },
+
+    /// Remove old image versions that are not used by a machine or a tag
+    Prune {
+        /// Number of newest versions of every image to keep
+        #[structopt(long, default_value = "3")]
+        keep: usize,
+        /// Only remove versions imported longer ago than this, e.g. 30d
+        #[structopt(long, parse(try_from_str = parse_duration))]
+        older_than: Option<u64>,
+        /// Print the versions to be removed without removing them
+        #[structopt(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(StructOpt)]
+enum BuildKeysOp {
+    /// List build keys
+    Ls,
+
+    /// Add a build key
+    Add {
+        /// SSH public key file
+        #[structopt(parse(from_os_str))]
+        key: PathBuf,
+        /// Name of the key in signatures, default: key comment
+        #[structopt(short, long)]
+        principal: Option<String>,
+    },
+
+    /// Remove a build key
+    Rm {
+        /// Principal of the build key to be removed
+        principal: String,
+    },
+}
+
+#[derive(StructOpt)]
+enum OverlaysOp {
+    /// List overlays of the field and the Seeds that boot with them
+    Ls,
+
+    /// Add or replace the field overlay, or the overlay of a profile
+    Add {
+        /// Directory to pack, or a cpio archive (plain or compressed)
+        #[structopt(parse(from_os_str))]
+        source: PathBuf,
+        /// Seed label the overlay is for, default: all Seeds of the field
+        #[structopt(short, long)]
+        profile: Option<String>,
+    },
+
+    /// Remove the field overlay, or the overlay of a profile
+    Rm {
+        /// Seed label of the overlay, default: the field overlay
+        #[structopt(short, long)]
+        profile: Option<String>,
+    },
+}
+
+#[derive(StructOpt)]
+enum SshCertOp {
+    /// List issued certificates
+    Ls,
+
+    /// Sign a user or automation public key
+    Sign(SignOpt),
+
+    /// Revoke a certificate by serial number
+    Revoke {
+        serial: String,
+    },
+}
+
+#[derive(StructOpt)]
+struct SignOpt {
+    /// SSH public key file to be signed
+    #[structopt(parse(from_os_str))]
+    key: PathBuf,
+    /// Admin key to sign with, default: an admin key of the field found in
+    /// ~/.ssh, use a .pub file to sign with a key held in ssh-agent
+    #[structopt(short = "s", long)]
+    ca: Option<PathBuf>,
+    /// Certificate identity, default: key file name
+    #[structopt(short = "I", long)]
+    id: Option<String>,
+    /// Comma-separated list of principals
+    #[structopt(short = "n", long, default_value = "root")]
+    principals: String,
+    /// Validity interval in ssh-keygen(1) format
+    #[structopt(short = "V", long, default_value = "+1d")]
+    validity: String,
+    /// Force this command to be run instead of a shell
+    #[structopt(long)]
+    force_command: Option<String>,
+    /// Comma-separated list of addresses the certificate is accepted from
+    #[structopt(long)]
+    source_address: Option<String>,
+}
+
+#[derive(StructOpt)]
+enum FieldOp {
+    /// Generate a new root CA cross-signed by the current one, and re-issue
+    /// machine certificates with it
+    RotateCa,
+
+    /// Change root.key password
+    Passwd {
+        /// Store root.key password in the Secret Service keyring
+        #[structopt(long)]
+        keyring: bool,
+        /// Seal root.key password in an age envelope that can be opened
+        /// with this SSH key loaded into ssh-agent
+        #[structopt(long)]
+        envelope: Option<PathBuf>,
     },
 }
 
@@ -499,13 +3061,87 @@ fn main() {
     match opt.op {
         None => { ls() },
         Some(Op::Fields) => { ls() },
-        Some(Op::New { name, key }) => { new(name, key) },
-        Some(Op::Images) => { ls_images() },
-        Some(Op::Import { path }) => { import(path) },
-        Some(Op::Start { image, version, seed, local, ca, network }) => {
-            Machine::new(image, version, opt.field, seed, local)
-                .start(ca, network)
-                .unwrap()
+        Some(Op::New { name, key, keyring, envelope }) => { new(name, key, keyring, envelope) },
+        Some(Op::Field { op: FieldOp::RotateCa }) => { rotate_ca(opt.field, opt.pass_fd) },
+        Some(Op::Field { op: FieldOp::Passwd { keyring, envelope } }) => {
+            passwd(opt.field, opt.pass_fd, keyring, envelope)
+        },
+        Some(Op::Admins { op: None }) => { ls_admins(opt.field) },
+        Some(Op::Admins { op: Some(AdminsOp::Ls) }) => { ls_admins(opt.field) },
+        Some(Op::Admins { op: Some(AdminsOp::Add { key, principals, expiry }) }) => {
+            add_admin(opt.field, key, principals, expiry)
+        },
+        Some(Op::Admins { op: Some(AdminsOp::Rm { comment }) }) => { rm_admin(opt.field, comment) },
+        Some(Op::BuildKeys { op: None }) => { ls_build_keys(opt.field) },
+        Some(Op::BuildKeys { op: Some(BuildKeysOp::Ls) }) => { ls_build_keys(opt.field) },
+        Some(Op::BuildKeys { op: Some(BuildKeysOp::Add { key, principal }) }) => {
+            add_build_key(opt.field, key, principal)
+        },
+        Some(Op::BuildKeys { op: Some(BuildKeysOp::Rm { principal }) }) => {
+            rm_build_key(opt.field, principal)
+        },
+        Some(Op::Overlays { op: None }) => { ls_overlays(opt.field) },
+        Some(Op::Overlays { op: Some(OverlaysOp::Ls) }) => { ls_overlays(opt.field) },
+        Some(Op::Overlays { op: Some(OverlaysOp::Add { source, profile }) }) => {
+            add_overlay(opt.field, source, profile)
+        },
+        Some(Op::Overlays { op: Some(OverlaysOp::Rm { profile }) }) => { rm_overlay(opt.field, profile) },
+        Some(Op::SshCert { op: SshCertOp::Ls }) => { ls_ssh_certs(opt.field) },
+        Some(Op::SshCert { op: SshCertOp::Sign(sign) }) => { sign_ssh_cert(opt.field, sign) },
+        Some(Op::SshCert { op: SshCertOp::Revoke { serial } }) => {
+            revoke_ssh_cert(opt.field, serial)
+        },
+        Some(Op::SshConfig) => { refresh_ssh_config(opt.field) },
+        Some(Op::Images { op: None }) => { ls_images() },
+        Some(Op::Images { op: Some(ImagesOp::Ls) }) => { ls_images() },
+        Some(Op::Images { op: Some(ImagesOp::Show { image, version, json }) }) => {
+            show_image(image, version, json)
+        },
+        Some(Op::Images { op: Some(ImagesOp::Diff { image, from, to }) }) => { diff_images(image, from, to) },
+        Some(Op::Images { op: Some(ImagesOp::Audit { image, tracker, release, all }) }) => {
+            audit_images(image, tracker, release, all)
+        },
+        Some(Op::Images { op: Some(ImagesOp::Rm { image, version }) }) => { rm_images(image, version) },
+        Some(Op::Images { op: Some(ImagesOp::Prune { keep, older_than, dry_run }) }) => {
+            prune_images(keep, older_than, dry_run)
+        },
+        Some(Op::Tag { image, version, tag }) => { tag_image(image, version, tag) },
+        Some(Op::Tags { image, history }) => { ls_tags(image, history) },
+        Some(Op::Import { source, name, version, signature }) => {
+            import(opt.field, source, name, version, signature)
+        },
+        Some(Op::Build { spec, version, sign }) => { build(opt.field, spec, version, sign) },
+        Some(Op::Push { image, version }) => { push(opt.field, image, version) },
+        Some(Op::Apply { path, dry_run }) => { apply(opt.field, opt.pass_fd, path, dry_run) },
+        Some(Op::Upgrade { target, version, ready }) => {
+            upgrade(opt.field, opt.pass_fd, target, version, ready)
+        },
+        Some(Op::Rollback { name, ready }) => { rollback(opt.field, opt.pass_fd, name, ready) },
+        Some(Op::Start {
+            image, version, name, seed, local, policy, memory, require, prefer, anti_affinity, ca,
+            network, volume,
+        }) => {
+            let placement = Placement {
+                seed,
+                local,
+                group: anti_affinity,
+                policy,
+                req: Requirements { memory, labels: require, prefer, ..Default::default() },
+            };
+            let machine = Machine::new(image, version, name, opt.field, placement, &volume);
+            let pw = if ca { machine.field.password(opt.pass_fd) } else { None };
+            machine.create(ca, pw, network, &volume).unwrap()
+        },
+        Some(Op::Seeds) => { ls_seeds(opt.field) },
+        Some(Op::Label { seed, labels, rm }) => { label(opt.field, seed, labels, rm) },
+        Some(Op::Machines) => { ls_machines(opt.field) },
+        Some(Op::Stop { name }) => { Field::select(opt.field).machine(&name).stop().unwrap() },
+        Some(Op::Restart { name }) => {
+            let field = Field::select(opt.field);
+            let machine = field.machine(&name);
+            let pw = if machine.has_ca() && !machine.is_running() { field.password(opt.pass_fd) } else { None };
+            machine.restart(pw).unwrap()
         },
+        Some(Op::Rm { name }) => { Field::select(opt.field).machine(&name).remove().unwrap() },
     };
 }
@@ -0,0 +1,185 @@
+use regex::Regex;
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::Error;
+use crate::image::Metadata;
+use crate::manifest::Volume;
+
+/// Image build spec for sow build
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Spec {
+    pub name: String,
+    /// Generated from the date when not set
+    pub version: Option<String>,
+    /// Imported image to start from, e.g. base or base:stable
+    pub base: Option<String>,
+    /// Debian suite to bootstrap when there's no base
+    pub suite: Option<String>,
+    /// APT mirror to bootstrap or build from, it ends up in sources.list of
+    /// the image, replacing the one of the base
+    pub mirror: Option<String>,
+    #[serde(default)]
+    pub steps: Vec<Step>,
+    pub network: Option<String>,
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    #[serde(default)]
+    pub volumes: Vec<Volume>,
+}
+
+/// Build step, one of apt, run, script, or copy
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    /// Packages to install
+    #[serde(default)]
+    pub apt: Vec<String>,
+    /// Shell commands to run inside the image
+    #[serde(default)]
+    pub run: Vec<String>,
+    /// Shell script to run inside the image, relative to the spec
+    pub script: Option<String>,
+    /// Files to copy into the image, relative to the spec
+    #[serde(default)]
+    pub copy: Vec<String>,
+    /// Destination of copied files, a directory when it ends with /
+    pub to: Option<String>,
+    /// User to run commands and scripts as
+    pub user: Option<String>,
+}
+
+impl Step {
+    pub fn kind(&self) -> Result<&'static str, Error> {
+        let kinds: Vec<&str> = vec![
+            Some("apt").filter(|_| !self.apt.is_empty()),
+            Some("run").filter(|_| !self.run.is_empty()),
+            Some("script").filter(|_| self.script.is_some()),
+            Some("copy").filter(|_| !self.copy.is_empty()),
+        ].into_iter().flatten().collect();
+        match kinds[..] {
+            [kind] => Ok(kind),
+            _ => Err(Error::ConfError(String::from(
+                "Every build step has exactly one of apt, run, script, or copy"))),
+        }
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        let kind = self.kind()?;
+        let package = Regex::new(r"^[[:alnum:]][[:alnum:]+.:=~-]*$").unwrap();
+        if let Some(p) = self.apt.iter().find(|p| !package.is_match(p)) {
+            return Err(Error::ConfError(format!("Invalid package name '{}'", p)));
+        }
+        match (kind, &self.to) {
+            ("copy", Some(to)) if to.starts_with('/') => (),
+            ("copy", _) => return Err(Error::ConfError(String::from(
+                "Copy steps need an absolute destination in 'to'"))),
+            (_, Some(_)) => return Err(Error::ConfError(String::from(
+                "Only copy steps have a destination"))),
+            _ => (),
+        }
+        match (kind, &self.user) {
+            ("run", Some(u)) | ("script", Some(u))
+                if !Regex::new(r"^[[:alnum:]_][[:alnum:]_.-]*$").unwrap().is_match(u) =>
+                return Err(Error::ConfError(format!("Invalid user '{}'", u))),
+            ("run", _) | ("script", _) => (),
+            (_, Some(_)) => return Err(Error::ConfError(String::from(
+                "Only run and script steps have a user"))),
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+impl Spec {
+    pub fn parse(spec: &str) -> Result<Self, Error> {
+        let spec: Spec = toml::from_str(spec)
+            .map_err(|err| Error::ConfError(err.to_string()))?;
+        if spec.base.is_some() && spec.suite.is_some() {
+            return Err(Error::ConfError(String::from(
+                "Build spec has both a base image and a suite to bootstrap")));
+        }
+        if let Some(mirror) = &spec.mirror {
+            if !Regex::new(r"^[[:alpha:]][[:alnum:]+]*://[[:alnum:]._~:/@%+=-]+$").unwrap().is_match(mirror) {
+                return Err(Error::ConfError(format!("Invalid mirror '{}'", mirror)));
+            }
+        }
+        for step in spec.steps.iter() {
+            step.check()?;
+        }
+        // same rules as image.toml
+        Metadata::parse(&spec.metadata("1").to_toml()?)?;
+        Ok(spec)
+    }
+
+    pub fn load(path: &PathBuf) -> Result<Self, Error> {
+        let spec = fs::read_to_string(path)
+            .map_err(|err| Error::ConfError(format!("Failed to read {:?}: {}", path, err)))?;
+        Self::parse(&spec)
+    }
+
+    pub fn suite(&self) -> &str {
+        self.suite.as_deref().unwrap_or("bookworm")
+    }
+
+    pub fn mirror(&self) -> &str {
+        self.mirror.as_deref().unwrap_or("http://deb.debian.org/debian")
+    }
+
+    pub fn metadata(&self, version: &str) -> Metadata {
+        Metadata {
+            name: self.name.to_string(),
+            version: Some(version.to_string()),
+            network: self.network.clone(),
+            ports: self.ports.clone(),
+            volumes: self.volumes.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spec() {
+        let spec = Spec::parse(r#"
+name = "postgres"
+base = "base:stable"
+ports = [5432]
+
+[[steps]]
+run = ["mkdir -p /etc/postgresql-common/createcluster.d"]
+
+[[steps]]
+apt = ["postgresql", "postgresql-contrib=16+257"]
+
+[[steps]]
+copy = ["postgresql.conf"]
+to = "/etc/postgresql/16/main/"
+
+[[volumes]]
+path = "/var/lib/postgresql"
+owner = "postgres"
+"#).unwrap();
+        let kinds: Vec<&str> = spec.steps.iter().map(|s| s.kind().unwrap()).collect();
+        assert_eq!(kinds, vec!["run", "apt", "copy"]);
+        assert_eq!(spec.metadata("20261010").volumes[0].to_string(), "/var/lib/postgresql:postgres");
+
+        let spec = Spec::parse("name = \"base\"\nmirror = \"http://apt.internal/debian\"\n").unwrap();
+        assert_eq!(spec.suite(), "bookworm");
+        assert_eq!(spec.mirror(), "http://apt.internal/debian");
+
+        assert!(Spec::parse("name = \"x\"\nbase = \"base\"\nsuite = \"trixie\"\n").is_err());
+        assert!(Spec::parse("name = \"x\"\nbase = \"base\"\nmirror = \"http://apt.internal/debian\"\n").is_ok());
+        assert!(Spec::parse("name = \"x\"\nmirror = \"http://apt.internal/debian main; reboot\"\n").is_err());
+        assert!(Spec::parse("name = \"x\"\n[[steps]]\napt = [\"a\"]\nrun = [\"b\"]\n").is_err());
+        assert!(Spec::parse("name = \"x\"\n[[steps]]\ncopy = [\"a\"]\nto = \"etc\"\n").is_err());
+        assert!(Spec::parse("name = \"x\"\n[[steps]]\napt = [\"a; reboot\"]\n").is_err());
+        assert!(Spec::parse("name = \"x\"\n[[steps]]\napt = [\"a\"]\nuser = \"nobody\"\n").is_err());
+        assert!(Spec::parse("name = \"x_y\"\n").is_err());
+    }
+}
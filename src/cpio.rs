@@ -0,0 +1,501 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tar::{Archive, EntryType};
+
+use crate::Error;
+use crate::image::normalize;
+
+const S_IFMT:  u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFIFO: u32 = 0o010000;
+
+/// Firmware directory and early microcode file name of each CPU vendor
+const MICROCODE: &[(&str, &str)] = &[
+    ("intel-ucode", "GenuineIntel.bin"),
+    ("amd-ucode", "AuthenticAMD.bin"),
+];
+
+/// Writer of newc cpio archives with the same modification time for every
+/// entry and inode numbers in the order of entries
+pub struct Writer<W: Write> {
+    out: W,
+    ino: u32,
+    mtime: u32,
+    offset: u64,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(out: W, mtime: u32) -> Self {
+        Writer { out, ino: 0, mtime, offset: 0 }
+    }
+
+    pub fn append<R: Read>(&mut self, entry: &Entry, data: R) -> Result<(), Error> {
+        self.ino += 1;
+        let nlink = match entry.mode & S_IFMT {
+            S_IFDIR => 2,
+            _ => 1,
+        };
+        self.header(self.ino, &entry.path, entry.mode, entry.uid, entry.gid, nlink, entry.size, entry.rdev)?;
+        let copied = io::copy(&mut data.take(entry.size), &mut self.out)?;
+        if copied != entry.size {
+            return Err(Error::from(format!("{} changed while reading it", &entry.path)));
+        }
+        self.offset += copied;
+        self.pad(4)
+    }
+
+    /// Regular file owned by root
+    pub fn append_file(&mut self, path: &str, mode: u32, data: &[u8]) -> Result<(), Error> {
+        self.append(&Entry::new(path, S_IFREG | mode, data.len() as u64), data)
+    }
+
+    /// Write the trailer and return the underlying writer
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.header(0, "TRAILER!!!", 0, 0, 0, 1, 0, (0, 0))?;
+        self.pad(512)?;
+        Ok(self.out)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn header(
+        &mut self,
+        ino: u32,
+        name: &str,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        nlink: u32,
+        size: u64,
+        rdev: (u32, u32),
+    ) -> Result<(), Error> {
+        let size = u32::try_from(size)
+            .map_err(|_| Error::from(format!("{} is too large for cpio", name)))?;
+        let header = format!(
+            "070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+            ino, mode, uid, gid, nlink, self.mtime, size, 0, 0, rdev.0, rdev.1, name.len() + 1, 0);
+        self.out.write_all(header.as_bytes())?;
+        self.out.write_all(name.as_bytes())?;
+        self.out.write_all(&[0])?;
+        self.offset += header.len() as u64 + name.len() as u64 + 1;
+        self.pad(4)
+    }
+
+    fn pad(&mut self, align: u64) -> Result<(), Error> {
+        let padding = (align - self.offset % align) % align;
+        self.out.write_all(&vec![0; padding as usize])?;
+        self.offset += padding;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+enum Data {
+    None,
+    Link(String),
+    File(PathBuf),
+    // offset in the spill file of a tarball
+    Spill(u64),
+}
+
+/// File, directory, symlink, or device node of a rootfs
+#[derive(Clone)]
+pub struct Entry {
+    /// Relative path, . for the root directory
+    pub path: String,
+    /// File type and permissions
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    /// Major and minor number of device nodes
+    pub rdev: (u32, u32),
+    data: Data,
+}
+
+impl Entry {
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    fn new(path: &str, mode: u32, size: u64) -> Self {
+        Entry { path: path.to_string(), mode, uid: 0, gid: 0, size, rdev: (0, 0), data: Data::None }
+    }
+}
+
+// Containers with private users (systemd-nspawn -U) are owned by a range of
+// 65536 host ids that starts with the owner of their root directory, map
+// them back to the ids inside the container
+fn unshift(entries: &mut [Entry]) {
+    let (uid, gid) = match entries.iter().find(|e| e.path == ".") {
+        Some(root) => (root.uid, root.gid),
+        None => return,
+    };
+    let shift = |base: u32, id: u32| match base != 0 && base & 0xffff == 0 && id >= base && id - base < 0x10000 {
+        true  => id - base,
+        false => id,
+    };
+    for entry in entries.iter_mut() {
+        entry.uid = shift(uid, entry.uid);
+        entry.gid = shift(gid, entry.gid);
+    }
+}
+
+/// Rootfs to pack into an initramfs, from a directory or a tarball
+pub struct Rootfs {
+    pub entries: Vec<Entry>,
+    spill: Option<File>,
+}
+
+impl Rootfs {
+    pub fn from_dir(dir: &Path) -> Result<Self, Error> {
+        let mut entries = vec![Self::dir_entry(dir, ".")?];
+        let mut dirs = vec![String::new()];
+        while let Some(parent) = dirs.pop() {
+            for child in fs::read_dir(dir.join(&parent))? {
+                let name = child?.file_name().into_string()
+                    .map_err(|name| Error::from(format!("Invalid file name {:?}", name)))?;
+                let path = match parent.is_empty() {
+                    true  => name,
+                    false => format!("{}/{}", &parent, &name),
+                };
+                let entry = Self::dir_entry(dir, &path)?;
+                if entry.mode & S_IFMT == S_IFDIR {
+                    dirs.push(path);
+                }
+                entries.push(entry);
+            }
+        }
+        unshift(&mut entries);
+        Ok(Rootfs { entries, spill: None })
+    }
+
+    fn dir_entry(dir: &Path, path: &str) -> Result<Entry, Error> {
+        let file = dir.join(path);
+        let meta = fs::symlink_metadata(&file)?;
+        let (size, data) = match meta.mode() & S_IFMT {
+            S_IFREG => (meta.size(), Data::File(file)),
+            S_IFLNK => {
+                let target = fs::read_link(&file)?.to_str()
+                    .ok_or_else(|| Error::from(format!("Invalid symlink {:?}", &file)))?.to_string();
+                (target.len() as u64, Data::Link(target))
+            },
+            _ => (0, Data::None),
+        };
+        // major and minor number encoding of glibc
+        let rdev = meta.rdev();
+        let rdev = (
+            (((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff)) as u32,
+            ((rdev & 0xff) | ((rdev >> 12) & !0xff)) as u32,
+        );
+        Ok(Entry { path: path.to_string(), mode: meta.mode(), uid: meta.uid(), gid: meta.gid(), size, rdev, data })
+    }
+
+    /// Read a tarball, storing file contents in spill so that they can be
+    /// written in a different order
+    pub fn from_tar<R: Read>(input: R, mut spill: File) -> Result<Self, Error> {
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut index = BTreeMap::new();
+        let mut offset = 0;
+        let mut archive = Archive::new(input);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = match normalize(&entry.path()?) {
+                p if p.is_empty() => String::from("."),
+                p => p,
+            };
+            let header = entry.header();
+            let (mode, uid, gid) = (header.mode()? & 0o7777, header.uid()? as u32, header.gid()? as u32);
+            let kind = header.entry_type();
+            let rdev = match kind {
+                EntryType::Char | EntryType::Block =>
+                    (header.device_major()?.unwrap_or(0), header.device_minor()?.unwrap_or(0)),
+                _ => (0, 0),
+            };
+            let link = entry.link_name()?.and_then(|l| l.to_str().map(|l| l.to_string()));
+            let (mode, size, data) = match kind {
+                EntryType::Directory => (S_IFDIR | mode, 0, Data::None),
+                EntryType::Regular | EntryType::Continuous => {
+                    let size = io::copy(&mut entry, &mut spill)?;
+                    offset += size;
+                    (S_IFREG | mode, size, Data::Spill(offset - size))
+                },
+                EntryType::Symlink => {
+                    let target = link.ok_or_else(|| Error::from(format!("Invalid symlink {}", &path)))?;
+                    (S_IFLNK | mode, target.len() as u64, Data::Link(target))
+                },
+                // hard links become copies of their target
+                EntryType::Link => {
+                    let target = link.map(|l| normalize(Path::new(&l)))
+                        .and_then(|l| index.get(&l).copied())
+                        .ok_or_else(|| Error::from(format!("Hard link {} has no target", &path)))?;
+                    let target: &Entry = &entries[target];
+                    (target.mode, target.size, target.data.clone())
+                },
+                EntryType::Char  => (S_IFCHR | mode, 0, Data::None),
+                EntryType::Block => (S_IFBLK | mode, 0, Data::None),
+                EntryType::Fifo  => (S_IFIFO | mode, 0, Data::None),
+                _ => continue,
+            };
+            let entry = Entry { path: path.to_string(), mode, uid, gid, size, rdev, data };
+            match index.get(&path) {
+                Some(&i) => entries[i] = entry,
+                None => {
+                    index.insert(path, entries.len());
+                    entries.push(entry);
+                },
+            }
+        }
+        Ok(Rootfs { entries, spill: Some(spill) })
+    }
+
+    pub fn open<'a>(&'a self, entry: &'a Entry) -> Result<Box<dyn Read + 'a>, Error> {
+        Ok(match &entry.data {
+            Data::None => Box::new(io::empty()),
+            Data::Link(target) => Box::new(target.as_bytes()),
+            Data::File(path) => Box::new(File::open(path)?),
+            Data::Spill(offset) => {
+                let mut spill = self.spill.as_ref().ok_or("Rootfs has no spill file")?;
+                spill.seek(SeekFrom::Start(*offset))?;
+                Box::new(spill)
+            },
+        })
+    }
+
+    /// Write all entries sorted by path, parents before their children
+    pub fn write<W: Write>(&self, writer: &mut Writer<W>) -> Result<(), Error> {
+        let mut entries: Vec<&Entry> = self.entries.iter().collect();
+        entries.sort_by(|a, b| (a.path != ".", &a.path).cmp(&(b.path != ".", &b.path)));
+        for entry in entries {
+            writer.append(entry, self.open(entry)?)?;
+        }
+        Ok(())
+    }
+
+    /// Microcode files under firmware, concatenated per vendor
+    pub fn microcode(&self, firmware: &str) -> Result<Vec<(&'static str, Vec<u8>)>, Error> {
+        let mut microcode = Vec::new();
+        for (dir, name) in MICROCODE {
+            let prefix = match firmware {
+                "" => format!("{}/", dir),
+                _  => format!("{}/{}/", firmware, dir),
+            };
+            let mut files: Vec<&Entry> = self.entries.iter()
+                .filter(|e| e.is_file())
+                .filter(|e| e.path.strip_prefix(&prefix).map(|f| !f.contains('/')).unwrap_or(false))
+                // amd-ucode also has .asc signatures
+                .filter(|e| *dir != "amd-ucode" || e.path.ends_with(".bin"))
+                .collect();
+            files.sort_by(|a, b| a.path.cmp(&b.path));
+            let mut blob = Vec::new();
+            for file in files {
+                self.open(file)?.take(file.size).read_to_end(&mut blob)?;
+            }
+            if !blob.is_empty() {
+                microcode.push((*name, blob));
+            }
+        }
+        Ok(microcode)
+    }
+
+    /// Total size of regular files by directory, up to depth levels deep,
+    /// largest first
+    pub fn sizes(&self, depth: usize) -> Vec<(String, u64)> {
+        let mut sizes = BTreeMap::new();
+        for entry in self.entries.iter().filter(|e| e.is_file()) {
+            let parts: Vec<&str> = entry.path.split('/').collect();
+            let dir = match parts[..(parts.len() - 1).min(depth)].join("/") {
+                d if d.is_empty() => String::from("."),
+                d => d,
+            };
+            *sizes.entry(dir).or_insert(0) += entry.size;
+        }
+        let mut sizes: Vec<(String, u64)> = sizes.into_iter().collect();
+        sizes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        sizes
+    }
+}
+
+/// Uncompressed early microcode archive that the kernel loads before it
+/// unpacks the rest of the initramfs
+pub fn write_microcode<W: Write>(out: W, microcode: &[(&str, Vec<u8>)], mtime: u32) -> Result<W, Error> {
+    let mut writer = Writer::new(out, mtime);
+    for dir in &["kernel", "kernel/x86", "kernel/x86/microcode"] {
+        writer.append(&Entry::new(dir, S_IFDIR | 0o755, 0), io::empty())?;
+    }
+    for (name, blob) in microcode {
+        writer.append_file(&format!("kernel/x86/microcode/{}", name), 0o644, blob)?;
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random_pw;
+    use std::process::{Command, Stdio};
+    use tar::{Builder, Header};
+
+    fn tarball(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for (path, data) in entries {
+            let mut header = Header::new_gnu();
+            header.set_mtime(1_700_000_000);
+            header.set_uid(0);
+            header.set_gid(0);
+            match (path.ends_with('/'), data.strip_prefix("=> ")) {
+                (true, _) => {
+                    header.set_entry_type(EntryType::Directory);
+                    header.set_mode(0o755);
+                    header.set_size(0);
+                    builder.append_data(&mut header, path, io::empty()).unwrap();
+                },
+                (false, Some(target)) => {
+                    header.set_entry_type(EntryType::Link);
+                    header.set_mode(0o644);
+                    header.set_size(0);
+                    builder.append_link(&mut header, path, target).unwrap();
+                },
+                (false, None) => {
+                    header.set_mode(0o644);
+                    header.set_size(data.len() as u64);
+                    builder.append_data(&mut header, path, data.as_bytes()).unwrap();
+                },
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+
+    // entry names of a newc archive
+    fn names(archive: &[u8]) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut offset = 0;
+        while offset < archive.len() && &archive[offset..offset + 6] == b"070701" {
+            let field = |i: usize| usize::from_str_radix(
+                std::str::from_utf8(&archive[offset + 6 + i * 8..offset + 14 + i * 8]).unwrap(), 16).unwrap();
+            let (size, namesize) = (field(6), field(11));
+            names.push(String::from_utf8(archive[offset + 110..offset + 109 + namesize].to_vec()).unwrap());
+            offset += 110 + namesize;
+            offset += (4 - offset % 4) % 4;
+            offset += size;
+            offset += (4 - offset % 4) % 4;
+        }
+        names
+    }
+
+    fn spill() -> File {
+        let path = std::env::temp_dir().join(format!("barley-test-cpio-{}", random_pw()));
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_writer() {
+        let mut writer = Writer::new(Vec::new(), 0);
+        writer.append_file("etc/hostname", 0o644, b"seed\n").unwrap();
+        let archive = writer.finish().unwrap();
+        assert_eq!(archive.len(), 512);
+        let header = ["070701", "00000001", "000081a4", "00000000", "00000000", "00000001", "00000000",
+            "00000005", "00000000", "00000000", "00000000", "00000000", "0000000d", "00000000"].concat();
+        assert_eq!(&archive[..110], header.as_bytes());
+        // 110 byte header and 13 byte name padded to 124, then the data
+        assert_eq!(&archive[110..124], b"etc/hostname\0\0");
+        assert_eq!(&archive[124..132], b"seed\n\0\0\0");
+        assert_eq!(&archive[132..138], b"070701");
+        assert_eq!(&archive[242..253], b"TRAILER!!!\0");
+        assert!(Writer::new(Vec::new(), 0).append(&Entry::new("big", S_IFREG | 0o644, 1 << 32), io::empty()).is_err());
+    }
+
+    #[test]
+    fn test_unshift() {
+        let entry = |path: &str, uid: u32| Entry { uid, gid: uid, ..Entry::new(path, S_IFREG | 0o644, 0) };
+        let ids = |entries: &[Entry]| -> Vec<(u32, u32)> { entries.iter().map(|e| (e.uid, e.gid)).collect() };
+        let mut shifted = vec![entry(".", 0x3f0e0000), entry("home/app", 0x3f0e0000 + 1000), entry("x", 1000)];
+        unshift(&mut shifted);
+        assert_eq!(ids(&shifted), vec![(0, 0), (1000, 1000), (1000, 1000)]);
+        // rootfs of a plain directory owned by a user
+        let mut plain = vec![entry(".", 1000), entry("home/app", 1000)];
+        unshift(&mut plain);
+        assert_eq!(ids(&plain), vec![(1000, 1000), (1000, 1000)]);
+
+        // as root, the same with a directory owned like one of systemd-nspawn -U
+        let dir = std::env::temp_dir().join(format!("barley-test-rootfs-{}", random_pw()));
+        fs::create_dir_all(dir.join("home")).unwrap();
+        fs::write(dir.join("home/motd"), "hello").unwrap();
+        let chown = |owner: &str, path: &Path| Command::new("chown").arg(owner).arg(path)
+            .stderr(Stdio::null()).status().map(|s| s.success()).unwrap_or(false);
+        if chown("65536:65536", &dir) && chown("65536:65536", &dir.join("home"))
+            && chown("66536:66537", &dir.join("home/motd")) {
+            let rootfs = Rootfs::from_dir(&dir).unwrap();
+            let motd = rootfs.entries.iter().find(|e| e.path == "home/motd").unwrap();
+            assert_eq!((motd.uid, motd.gid), (1000, 1001));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rootfs() {
+        let input = tarball(&[
+            ("./", ""),
+            ("./usr/", ""),
+            ("./usr/lib/", ""),
+            ("./usr/lib/firmware/", ""),
+            ("./usr/lib/firmware/amd-ucode/", ""),
+            ("./usr/lib/firmware/amd-ucode/microcode_amd_fam19h.bin", "amd19"),
+            ("./usr/lib/firmware/amd-ucode/microcode_amd_fam17h.bin", "amd17"),
+            ("./usr/lib/firmware/amd-ucode/microcode_amd_fam17h.bin.asc", "sig"),
+            ("./usr/lib/firmware/intel-ucode/", ""),
+            ("./usr/lib/firmware/intel-ucode/06-8e-09", "intel"),
+            ("./etc/", ""),
+            ("./etc/motd", "hello"),
+            ("./etc/issue", "=> etc/motd"),
+            ("./usr/lib/os-release", "ID=debian\n"),
+        ]);
+        let rootfs = Rootfs::from_tar(&input[..], spill()).unwrap();
+        let mut writer = Writer::new(Vec::new(), 0);
+        rootfs.write(&mut writer).unwrap();
+        let archive = writer.finish().unwrap();
+
+        // same content in a different order gives the same archive
+        let mut shuffled = Rootfs::from_tar(&input[..], spill()).unwrap();
+        shuffled.entries.reverse();
+        let mut writer = Writer::new(Vec::new(), 0);
+        shuffled.write(&mut writer).unwrap();
+        assert_eq!(writer.finish().unwrap(), archive);
+
+        assert_eq!(names(&archive), vec![
+            ".", "etc", "etc/issue", "etc/motd", "usr", "usr/lib", "usr/lib/firmware",
+            "usr/lib/firmware/amd-ucode",
+            "usr/lib/firmware/amd-ucode/microcode_amd_fam17h.bin",
+            "usr/lib/firmware/amd-ucode/microcode_amd_fam17h.bin.asc",
+            "usr/lib/firmware/amd-ucode/microcode_amd_fam19h.bin",
+            "usr/lib/firmware/intel-ucode", "usr/lib/firmware/intel-ucode/06-8e-09",
+            "usr/lib/os-release", "TRAILER!!!",
+        ]);
+        assert!(String::from_utf8_lossy(&archive).contains("etc/issue\0hello"));
+
+        let microcode = rootfs.microcode("usr/lib/firmware").unwrap();
+        assert_eq!(microcode, vec![
+            ("GenuineIntel.bin", b"intel".to_vec()),
+            ("AuthenticAMD.bin", b"amd17amd19".to_vec()),
+        ]);
+        assert!(rootfs.microcode("lib/firmware").unwrap().is_empty());
+        let early = write_microcode(Vec::new(), &microcode, 0).unwrap();
+        assert_eq!(names(&early)[3..], ["kernel/x86/microcode/GenuineIntel.bin", "kernel/x86/microcode/AuthenticAMD.bin", "TRAILER!!!"]);
+        assert_eq!(early.len() % 512, 0);
+
+        assert_eq!(rootfs.sizes(2), vec![
+            (String::from("usr/lib"), 28),
+            (String::from("etc"), 10),
+        ]);
+        assert_eq!(rootfs.sizes(0), vec![(String::from("."), 38)]);
+    }
+}
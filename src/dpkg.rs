@@ -0,0 +1,261 @@
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+
+/// dpkg database of installed packages inside the image rootfs
+pub const STATUS: &str = "var/lib/dpkg/status";
+
+/// Installed Debian package
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    pub arch: String,
+    /// Source package, same as the name unless the Source field says otherwise
+    pub source: String,
+    pub source_version: String,
+}
+
+/// Installed packages from a dpkg status file, sorted by name
+pub fn parse_status(status: &str) -> Vec<Package> {
+    let mut packages: Vec<Package> = status.split("\n\n").filter_map(|stanza| {
+        let mut fields = BTreeMap::new();
+        for line in stanza.lines() {
+            // continuation lines of multi-line fields start with a space
+            if line.starts_with(' ') || line.starts_with('\t') {
+                continue;
+            }
+            let mut field = line.splitn(2, ':');
+            if let (Some(key), Some(value)) = (field.next(), field.next()) {
+                fields.insert(key, value.trim());
+            }
+        }
+        if fields.get("Status")?.split_whitespace().nth(2) != Some("installed") {
+            return None;
+        }
+        let name = fields.get("Package")?.to_string();
+        let version = fields.get("Version")?.to_string();
+        // Source: openssl (3.0.11-1~deb12u2)
+        let (source, source_version) = match fields.get("Source") {
+            Some(s) => match s.splitn(2, ' ').collect::<Vec<&str>>()[..] {
+                [s, v] => (s.to_string(), v.trim_matches(|c| c == '(' || c == ')').to_string()),
+                _      => (s.to_string(), version.to_string()),
+            },
+            None => (name.to_string(), version.to_string()),
+        };
+        Some(Package {
+            name,
+            version,
+            arch: fields.get("Architecture").unwrap_or(&"").to_string(),
+            source,
+            source_version,
+        })
+    }).collect();
+    packages.sort_by(|a, b| a.name.cmp(&b.name).then(a.arch.cmp(&b.arch)));
+    packages
+}
+
+/// Debian version ordering, [epoch:]upstream_version[-debian_revision]
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a_epoch, a_upstream, a_revision) = split_version(a);
+    let (b_epoch, b_upstream, b_revision) = split_version(b);
+    a_epoch.cmp(&b_epoch)
+        .then_with(|| verrevcmp(a_upstream, b_upstream))
+        .then_with(|| verrevcmp(a_revision, b_revision))
+}
+
+fn split_version(version: &str) -> (u64, &str, &str) {
+    let (epoch, rest) = match version.splitn(2, ':').collect::<Vec<&str>>()[..] {
+        [e, rest] => (e.parse().unwrap_or(0), rest),
+        _         => (0, version),
+    };
+    match rest.rsplitn(2, '-').collect::<Vec<&str>>()[..] {
+        [revision, upstream] => (epoch, upstream, revision),
+        _                    => (epoch, rest, ""),
+    }
+}
+
+// ~ sorts before anything, even the end of the version, letters sort before
+// other characters
+fn order(c: u8) -> i32 {
+    match c {
+        b'~' => -1,
+        0 => 0,
+        c if c.is_ascii_digit() => 0,
+        c if c.is_ascii_alphabetic() => c as i32,
+        c => c as i32 + 256,
+    }
+}
+
+// verrevcmp() of dpkg, alternating non-digit and digit parts
+fn verrevcmp(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let at = |s: &[u8], i: usize| s.get(i).copied().unwrap_or(0);
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !a[i].is_ascii_digit()) || (j < b.len() && !b[j].is_ascii_digit()) {
+            let (ac, bc) = (order(at(a, i)), order(at(b, j)));
+            if ac != bc {
+                return ac.cmp(&bc);
+            }
+            i += 1;
+            j += 1;
+        }
+        while at(a, i) == b'0' {
+            i += 1;
+        }
+        while at(b, j) == b'0' {
+            j += 1;
+        }
+        let mut first_diff = Ordering::Equal;
+        while at(a, i).is_ascii_digit() && at(b, j).is_ascii_digit() {
+            if first_diff == Ordering::Equal {
+                first_diff = a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+        if at(a, i).is_ascii_digit() {
+            return Ordering::Greater;
+        }
+        if at(b, j).is_ascii_digit() {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+    Ordering::Equal
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Change<'a> {
+    Added(&'a Package),
+    Removed(&'a Package),
+    Upgraded(&'a Package, &'a Package),
+    Downgraded(&'a Package, &'a Package),
+}
+
+impl Display for Change<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added(p)   => write!(f, "added      {} {}", p.name, p.version),
+            Change::Removed(p) => write!(f, "removed    {} {}", p.name, p.version),
+            Change::Upgraded(a, b) =>
+                write!(f, "upgraded   {} {} -> {}", a.name, a.version, b.version),
+            Change::Downgraded(a, b) =>
+                write!(f, "downgraded {} {} -> {}", a.name, a.version, b.version),
+        }
+    }
+}
+
+/// Package changes from old to new, sorted by package name
+pub fn diff<'a>(old: &'a [Package], new: &'a [Package]) -> Vec<Change<'a>> {
+    let key = |p: &'a Package| (p.name.as_str(), p.arch.as_str());
+    let old: BTreeMap<_, _> = old.iter().map(|p| (key(p), p)).collect();
+    let new: BTreeMap<_, _> = new.iter().map(|p| (key(p), p)).collect();
+    let mut keys: Vec<_> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    keys.into_iter().filter_map(|k| match (old.get(k), new.get(k)) {
+        (Some(a), None) => Some(Change::Removed(a)),
+        (None, Some(b)) => Some(Change::Added(b)),
+        (Some(a), Some(b)) => match compare_versions(&a.version, &b.version) {
+            Ordering::Less    => Some(Change::Upgraded(a, b)),
+            Ordering::Greater => Some(Change::Downgraded(a, b)),
+            Ordering::Equal   => None,
+        },
+        (None, None) => None,
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS_OLD: &str = "\
+Package: libssl3
+Status: install ok installed
+Architecture: amd64
+Source: openssl (3.0.11-1~deb12u1)
+Version: 3.0.11-1~deb12u1
+Description: Secure Sockets Layer toolkit
+ This package is part of the OpenSSL project.
+
+Package: postgresql-16
+Status: install ok installed
+Architecture: amd64
+Version: 16.3-1.pgdg120+1
+
+Package: telnet
+Status: deinstall ok config-files
+Architecture: amd64
+Version: 0.17+2.4-2
+
+Package: vim-tiny
+Status: install ok installed
+Architecture: amd64
+Source: vim
+Version: 2:9.0.1378-2
+";
+
+    const STATUS_NEW: &str = "\
+Package: libssl3
+Status: install ok installed
+Architecture: amd64
+Source: openssl (3.0.14-1~deb12u2)
+Version: 3.0.14-1~deb12u2
+
+Package: postgresql-16
+Status: hold ok installed
+Architecture: amd64
+Version: 16.4-1.pgdg120+1
+
+Package: curl
+Status: install ok installed
+Architecture: amd64
+Version: 7.88.1-10+deb12u7
+";
+
+    #[test]
+    fn test_status() {
+        let packages = parse_status(STATUS_OLD);
+        let names: Vec<&str> = packages.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["libssl3", "postgresql-16", "vim-tiny"]);
+        assert_eq!(packages[0].source, "openssl");
+        assert_eq!(packages[0].source_version, "3.0.11-1~deb12u1");
+        assert_eq!(packages[2].source, "vim");
+        assert_eq!(packages[2].source_version, "2:9.0.1378-2");
+        assert_eq!(packages[1].source, "postgresql-16");
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        let less = |a, b| compare_versions(a, b) == Ordering::Less;
+        assert!(less("1.0", "1.1"));
+        assert!(less("1.0~rc1", "1.0"));
+        assert!(less("1.0", "1.0+b1"));
+        assert!(less("1.0-1", "1.0-1.1"));
+        assert!(less("9.0", "1:1.0"));
+        assert!(less("1.2.9", "1.2.10"));
+        assert!(less("3.0.11-1~deb12u1", "3.0.11-1"));
+        assert!(less("1.0a", "1.0+"));
+        assert!(less("1.0~~", "1.0~"));
+        assert_eq!(compare_versions("1.01", "1.1"), Ordering::Equal);
+        assert_eq!(compare_versions("0:1.0-1", "1.0-1"), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = parse_status(STATUS_OLD);
+        let new = parse_status(STATUS_NEW);
+        let changes: Vec<String> = diff(&old, &new).iter().map(|c| c.to_string()).collect();
+        assert_eq!(changes, vec![
+            "added      curl 7.88.1-10+deb12u7",
+            "upgraded   libssl3 3.0.11-1~deb12u1 -> 3.0.14-1~deb12u2",
+            "upgraded   postgresql-16 16.3-1.pgdg120+1 -> 16.4-1.pgdg120+1",
+            "removed    vim-tiny 2:9.0.1378-2",
+        ]);
+    }
+}
@@ -0,0 +1,480 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use tar::{Archive, EntryType};
+
+use crate::Error;
+use crate::dpkg::{self, Package};
+use crate::manifest::Volume;
+
+/// Path of the metadata file inside the image rootfs
+pub const METADATA: &str = "etc/barley/image.toml";
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Metadata {
+    pub name:    String,
+    pub version: Option<String>,
+    pub built:   Option<String>,
+    pub base:    Option<String>,
+    pub commit:  Option<String>,
+    /// bridge (default), host, or none
+    pub network: Option<String>,
+    /// command and environment of images imported from OCI images, machines
+    /// still boot the image with its init
+    #[serde(default)]
+    pub entrypoint: Vec<String>,
+    #[serde(default)]
+    pub env:     Vec<String>,
+    #[serde(default)]
+    pub ports:   Vec<u16>,
+    #[serde(default)]
+    pub volumes: Vec<Volume>,
+}
+
+impl Metadata {
+    pub fn parse(metadata: &str) -> Result<Self, Error> {
+        let metadata: Metadata = toml::from_str(metadata)
+            .map_err(|err| Error::ConfError(err.to_string()))?;
+        if !valid_name(&metadata.name) {
+            return Err(Error::ConfError(format!("Invalid image name '{}'", &metadata.name)));
+        }
+        if let Some(version) = &metadata.version {
+            if !valid_version(version) {
+                return Err(Error::ConfError(format!("Invalid image version '{}'", version)));
+            }
+        }
+        for volume in metadata.volumes.iter() {
+            Volume::parse(&volume.to_string())?;
+        }
+        metadata.network_config()?;
+        Ok(metadata)
+    }
+
+    /// None when the image has no metadata file
+    pub fn read(image: &PathBuf) -> Result<Option<Self>, Error> {
+        let mut zstd = Command::new("zstd").arg("-dcq").arg(image).stdout(Stdio::piped()).spawn()?;
+        let stdout = zstd.stdout.take().ok_or("Child process stdout has not been captured.")?;
+        let mut metadata = String::new();
+        let mut archive = Archive::new(stdout);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            // etc/barley/image.toml or ./etc/barley/image.toml, nowhere else
+            let path = entry.path()?.into_owned();
+            if path.strip_prefix(".").unwrap_or(&path) == Path::new(METADATA)
+                && entry.header().entry_type().is_file() {
+                metadata.clear();
+                entry.read_to_string(&mut metadata)?;
+            }
+        }
+        // zstd fails with EPIPE unless the padding after the last entry is read
+        io::copy(&mut archive.into_inner(), &mut io::sink())?;
+        if !zstd.wait()?.success() {
+            return Err(Error::from(format!("Failed to decompress {:?}", image)));
+        }
+        match metadata.is_empty() {
+            true  => Ok(None),
+            false => Self::parse(&metadata).map(Some),
+        }
+    }
+
+    pub fn to_toml(&self) -> Result<String, Error> {
+        toml::to_string(&self).map_err(|err| Error::ConfError(err.to_string()))
+    }
+
+    /// systemd.nspawn(5) [Network] lines for the network mode, None for br0
+    pub fn network_config(&self) -> Result<Option<Vec<String>>, Error> {
+        let lines = |l: &[&str]| Ok(Some(l.iter().map(|s| s.to_string()).collect()));
+        match self.network.as_deref() {
+            None | Some("bridge") => Ok(None),
+            Some("host") => lines(&["Private=no", "VirtualEthernet=no"]),
+            Some("none") => lines(&["Private=yes", "VirtualEthernet=no"]),
+            Some(mode) => Err(Error::ConfError(format!(
+                "Unknown network mode '{}', use bridge, host, or none", mode))),
+        }
+    }
+}
+
+/// Image names end at the first _ of the file name, versions follow it
+pub fn valid_name(name: &str) -> bool {
+    Regex::new(r"^[[:alnum:]][[:alnum:].+-]*$").unwrap().is_match(name)
+}
+
+pub fn valid_version(version: &str) -> bool {
+    Regex::new(r"^[[:alnum:]][[:alnum:]_.+~-]*$").unwrap().is_match(version)
+}
+
+/// Name and version from the file name of a rootfs tarball, e.g.
+/// name_version.tar.zst, name.tar.xz, or https://mirror/name_version.tar.
+/// The version is empty when the file name has none.
+pub fn source_name(source: &str) -> (String, String) {
+    let file = source.rsplit('/').next().unwrap_or(source);
+    let stem = [".tar.zst", ".tar.xz", ".tar.gz", ".tar"].iter()
+        .find_map(|ext| file.strip_suffix(ext))
+        .unwrap_or(file);
+    let mut parts = stem.splitn(2, '_');
+    let name = parts.next().unwrap_or("");
+    (name.to_string(), parts.next().unwrap_or("").to_string())
+}
+
+/// Import time of an image from the "seconds stem" lines of the import log,
+/// the last one counts when a version was removed and imported again
+pub fn import_time(imports: &str, stem: &str) -> Option<u64> {
+    imports.lines().rev().filter_map(|line| {
+        let mut f = line.split_whitespace();
+        let time = f.next()?.parse().ok()?;
+        match f.next() {
+            Some(s) if s == stem => Some(time),
+            _ => None,
+        }
+    }).next()
+}
+
+/// Compress a rootfs tarball with zstd unless it already is
+pub fn recompress<R: Read>(input: R, rootfs: &PathBuf) -> Result<(), Error> {
+    let mut input = BufReader::new(input);
+    let magic = input.fill_buf()?.to_vec();
+    if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        io::copy(&mut input, &mut File::create(rootfs)?)?;
+        return Ok(());
+    }
+    let decompress = if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0]) {
+        Some("xz")
+    } else if magic.starts_with(&[0x1f, 0x8b]) {
+        Some("gzip")
+    } else {
+        None
+    };
+    let mut zstd = Command::new("zstd").arg("-qT0").arg("-o").arg(rootfs)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    let mut children = vec![];
+    let mut stdin = zstd.stdin.take().ok_or("Child process stdin has not been captured.")?;
+    if let Some(decompress) = decompress {
+        let mut child = Command::new(decompress).arg("-dc")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::from(stdin))
+            .spawn()?;
+        let pipe = child.stdin.take().ok_or("Child process stdin has not been captured.")?;
+        children.push((decompress, child));
+        io::copy(&mut input, &mut { pipe })?;
+    } else {
+        io::copy(&mut input, &mut stdin)?;
+        drop(stdin);
+    }
+    children.push(("zstd", zstd));
+    for (name, mut child) in children {
+        if !child.wait()?.success() {
+            return Err(Error::from(format!("{} failed", name)));
+        }
+    }
+    Ok(())
+}
+
+// etc/os-release is usually a link to usr/lib/os-release
+const OS_RELEASE: &[&str] = &["etc/os-release", "usr/lib/os-release"];
+
+/// Files and Debian packages of an image
+pub struct Contents {
+    /// SHA256 of regular files (just "regular" from list), link targets, or
+    /// entry types of the rest
+    pub files: BTreeMap<String, String>,
+    pub packages: Vec<Package>,
+    /// VERSION_CODENAME from os-release, e.g. bookworm
+    pub release: Option<String>,
+}
+
+impl Contents {
+    pub fn read(image: &PathBuf) -> Result<Self, Error> {
+        Self::read_files(image, true)
+    }
+
+    /// Same as read, but regular files are not hashed, for when only the
+    /// list of files and packages matters
+    pub fn list(image: &PathBuf) -> Result<Self, Error> {
+        Self::read_files(image, false)
+    }
+
+    fn read_files(image: &PathBuf, hash: bool) -> Result<Self, Error> {
+        let mut zstd = Command::new("zstd").arg("-dcq").arg(image).stdout(Stdio::piped()).spawn()?;
+        let stdout = zstd.stdout.take().ok_or("Child process stdout has not been captured.")?;
+        let mut files = BTreeMap::new();
+        let mut status = String::new();
+        let mut os_release = String::new();
+        let mut archive = Archive::new(stdout);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = normalize(&entry.path()?);
+            let file = match entry.header().entry_type() {
+                EntryType::Regular | EntryType::Continuous => {
+                    let mut hasher = Sha256::new();
+                    if path == dpkg::STATUS {
+                        entry.read_to_string(&mut status)?;
+                        hasher.update(status.as_bytes());
+                    } else if OS_RELEASE.contains(&path.as_str()) && os_release.is_empty() {
+                        entry.read_to_string(&mut os_release)?;
+                        hasher.update(os_release.as_bytes());
+                    } else if hash {
+                        io::copy(&mut entry, &mut hasher)?;
+                    }
+                    match hash {
+                        true  => format!("{:x}", hasher.finalize()),
+                        false => String::from("regular"),
+                    }
+                },
+                EntryType::Symlink | EntryType::Link => match entry.link_name()? {
+                    Some(target) => format!("-> {}", target.display()),
+                    None         => String::from("->"),
+                },
+                t => format!("{:?}", t).to_lowercase(),
+            };
+            files.insert(path, file);
+        }
+        // zstd fails with EPIPE unless the padding after the last entry is read
+        io::copy(&mut archive.into_inner(), &mut io::sink())?;
+        if !zstd.wait()?.success() {
+            return Err(Error::from(format!("Failed to decompress {:?}", image)));
+        }
+        let release = os_release.lines()
+            .find_map(|l| l.strip_prefix("VERSION_CODENAME="))
+            .map(|r| r.trim_matches('"').to_string())
+            .filter(|r| !r.is_empty());
+        Ok(Contents { files, packages: dpkg::parse_status(&status), release })
+    }
+
+    /// Files added (+), removed (-), and changed (~) in the new image
+    pub fn diff<'a>(&'a self, new: &'a Contents) -> Vec<(char, &'a str)> {
+        let mut changes: Vec<(char, &str)> = self.files.iter()
+            .filter_map(|(path, old)| match new.files.get(path) {
+                None => Some(('-', path.as_str())),
+                Some(file) if file != old => Some(('~', path.as_str())),
+                Some(_) => None,
+            })
+            .chain(new.files.keys()
+                .filter(|path| !self.files.contains_key(*path))
+                .map(|path| ('+', path.as_str())))
+            .collect();
+        changes.sort_by(|a, b| a.1.cmp(b.1));
+        changes
+    }
+}
+
+// archive paths relative to the root, without ./ or trailing /
+pub(crate) fn normalize(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(c) => c.to_str(),
+            _ => None,
+        })
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+/// Tag move, one line "time image tag version" of the tag history
+#[derive(Debug, PartialEq)]
+pub struct Tag {
+    pub time: String,
+    pub image: String,
+    pub tag: String,
+    pub version: String,
+}
+
+impl Tag {
+    /// Tags start with a letter so that they can stand in for date versions
+    pub fn valid(tag: &str) -> bool {
+        Regex::new(r"^[[:alpha:]][[:alnum:]_.-]*$").unwrap().is_match(tag)
+    }
+
+    pub fn parse_history(history: &str) -> Vec<Tag> {
+        history.lines().filter_map(|line| {
+            let f: Vec<&str> = line.split_whitespace().collect();
+            match f.len() {
+                4 => Some(Tag {
+                    time: f[0].to_string(),
+                    image: f[1].to_string(),
+                    tag: f[2].to_string(),
+                    version: f[3].to_string(),
+                }),
+                _ => None,
+            }
+        }).collect()
+    }
+
+    /// Last move of every tag, sorted by image and tag
+    pub fn current(history: &[Tag]) -> Vec<&Tag> {
+        let mut tags = BTreeMap::new();
+        for tag in history {
+            tags.insert((&tag.image, &tag.tag), tag);
+        }
+        tags.values().copied().collect()
+    }
+}
+
+impl Display for Tag {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {} {}", self.time, self.image, self.tag, self.version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata() {
+        let m = Metadata::parse(r#"
+name = "postgres"
+version = "20261001"
+built = "2026-10-01T12:00:00Z"
+base = "base_20260930"
+commit = "3f2a9c1"
+ports = [5432]
+network = "host"
+
+[[volumes]]
+path = "/var/lib/postgresql"
+owner = "postgres"
+size = "32G"
+"#).unwrap();
+        assert_eq!(m.ports, vec![5432]);
+        assert_eq!(m.volumes[0].to_string(), "/var/lib/postgresql:postgres:32G");
+        assert_eq!(m.network_config().unwrap().unwrap(), vec!["Private=no", "VirtualEthernet=no"]);
+        assert_eq!(Metadata::parse(&m.to_toml().unwrap()).unwrap().commit.as_deref(), Some("3f2a9c1"));
+
+        let m = Metadata::parse("name = \"web\"\n").unwrap();
+        assert!(m.version.is_none());
+        assert!(m.network_config().unwrap().is_none());
+
+        assert!(Metadata::parse("name = \"web_1\"\n").is_err());
+        assert!(Metadata::parse("name = \"web\"\nnetwork = \"veth\"\n").is_err());
+        assert!(Metadata::parse("name = \"web\"\n[[volumes]]\npath = \"data\"\nowner = \"web\"\n").is_err());
+    }
+
+    fn image(files: &[(&str, &str)]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("barley-test-{}.tar.zst", crate::random_pw()));
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, data.as_bytes()).unwrap();
+        }
+        let mut zstd = Command::new("zstd").arg("-qo").arg(&path).stdin(Stdio::piped()).spawn().unwrap();
+        io::Write::write_all(zstd.stdin.as_mut().unwrap(), &builder.into_inner().unwrap()).unwrap();
+        drop(zstd.stdin.take());
+        assert!(zstd.wait().unwrap().success());
+        path
+    }
+
+    #[test]
+    fn test_read_metadata() {
+        let path = image(&[("./etc/barley/image.toml", "name = \"web\"\n")]);
+        assert_eq!(Metadata::read(&path).unwrap().unwrap().name, "web");
+        std::fs::remove_file(&path).unwrap();
+
+        let path = image(&[("opt/app/etc/barley/image.toml", "name = \"web\"\n")]);
+        assert!(Metadata::read(&path).unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+
+        let path = std::env::temp_dir().join(format!("barley-test-{}.tar.zst", crate::random_pw()));
+        std::fs::write(&path, "not an image").unwrap();
+        assert!(Metadata::read(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_source_name() {
+        let name = |s: &str| {
+            let (name, version) = source_name(s);
+            format!("{} {}", name, version)
+        };
+        assert_eq!(name("/srv/postgres_20261001.tar.zst"), "postgres 20261001");
+        assert_eq!(name("nginx.tar.xz"), "nginx ");
+        assert_eq!(name("https://mirror/web_1.2_3.tar.gz"), "web 1.2_3");
+        assert_eq!(name("rootfs"), "rootfs ");
+        assert!(valid_name("web") && !valid_name("web_1") && !valid_name("../web") && !valid_name(""));
+        assert!(valid_version("1.2~rc1") && !valid_version("1/2") && !valid_version("-1"));
+    }
+
+    #[test]
+    fn test_recompress() {
+        let data = "rootfs tarball, or close enough";
+        for compress in &[None, Some("xz"), Some("gzip"), Some("zstd")] {
+            let input = match compress {
+                Some(c) => {
+                    let mut child = Command::new(c).arg("-c")
+                        .stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+                    io::Write::write_all(child.stdin.as_mut().unwrap(), data.as_bytes()).unwrap();
+                    drop(child.stdin.take());
+                    child.wait_with_output().unwrap().stdout
+                },
+                None => data.as_bytes().to_vec(),
+            };
+            let path = std::env::temp_dir().join(format!("barley-test-{}.tar.zst", crate::random_pw()));
+            recompress(&input[..], &path).unwrap();
+            let output = Command::new("zstd").arg("-dcq").arg(&path).output().unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), data, "{:?}", compress);
+        }
+    }
+
+    #[test]
+    fn test_contents() {
+        let contents = |files: &[(&str, &str)]| Contents {
+            files: files.iter().map(|(p, f)| (p.to_string(), f.to_string())).collect(),
+            packages: Vec::new(),
+            release: None,
+        };
+        let path = image(&[("etc/motd", "hello\n")]);
+        assert_eq!(Contents::read(&path).unwrap().files["etc/motd"],
+            "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03");
+        assert_eq!(Contents::list(&path).unwrap().files["etc/motd"], "regular");
+        std::fs::remove_file(&path).unwrap();
+
+        let old = contents(&[("etc", "directory"), ("etc/motd", "01"), ("usr/bin/vi", "02")]);
+        let new = contents(&[("etc", "directory"), ("etc/motd", "03"), ("usr/bin/curl", "04")]);
+        assert_eq!(old.diff(&new), vec![('~', "etc/motd"), ('+', "usr/bin/curl"), ('-', "usr/bin/vi")]);
+        assert!(new.diff(&new).is_empty());
+    }
+
+    #[test]
+    fn test_import_time() {
+        let imports = "\
+1790000000 postgres_20261001
+1790000100 postgres_20261002
+broken
+1790000200 postgres_20261001
+";
+        assert_eq!(import_time(imports, "postgres_20261001"), Some(1790000200));
+        assert_eq!(import_time(imports, "postgres_20261002"), Some(1790000100));
+        assert_eq!(import_time(imports, "postgres_2026100"), None);
+    }
+
+    #[test]
+    fn test_tags() {
+        let history = Tag::parse_history("\
+2026-10-01T10:00:00 postgres stable 20260901
+2026-10-01T10:05:00 postgres testing 20261001
+broken line
+2026-10-08T09:00:00 postgres stable 20261001
+2026-10-08T09:30:00 nginx stable 20261002
+");
+        assert_eq!(history.len(), 4);
+        assert_eq!(history[0].to_string(), "2026-10-01T10:00:00 postgres stable 20260901");
+        let current: Vec<String> = Tag::current(&history).iter()
+            .map(|t| format!("{}:{}={}", t.image, t.tag, t.version)).collect();
+        assert_eq!(current, vec![
+            "nginx:stable=20261002", "postgres:stable=20261001", "postgres:testing=20261001",
+        ]);
+        assert!(Tag::valid("stable"));
+        assert!(!Tag::valid("20261001"));
+        assert!(!Tag::valid("my tag"));
+    }
+}
@@ -7,7 +7,17 @@ use std::convert::From;
 use std::fmt::{Debug, Display, Formatter};
 use std::path::PathBuf;
 use std::process::Command;
-
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub mod build;
+pub mod cpio;
+pub mod dpkg;
+pub mod image;
+pub mod manifest;
+pub mod oci;
+pub mod schedule;
+pub mod secret;
+pub mod security;
 pub mod ssh;
 pub mod tls;
 
@@ -17,6 +27,7 @@ pub struct Certs {
     host:  String,
     ca:    String,
     cert:  String,
+    token: String,
 }
 
 #[derive(Deserialize)]
@@ -27,6 +38,50 @@ pub struct Registration {
     csr: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct SeedInfo {
+    pub name:   String,
+    pub ip:     String,
+    #[serde(default)]
+    pub labels: Vec<String>,
+    pub status: Option<Status>,
+}
+
+// machine that Sower keeps running on a Seed
+#[derive(Serialize, Deserialize)]
+pub struct Assignment {
+    pub name:    String,
+    /// image file stem, name_version
+    pub image:   String,
+    #[serde(default)]
+    pub network: Option<Vec<String>>,
+    #[serde(default)]
+    pub volumes: Vec<String>,
+    #[serde(default)]
+    pub stopped: bool,
+}
+
+// reported by barley-refresh on the Seed
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub mem_total: u64,
+    pub mem_free:  u64,
+    pub cpus:      u32,
+    pub load:      f32,
+    pub disk_free: u64,
+    pub machines:  u32,
+    #[serde(default)]
+    pub running:   Vec<String>,
+    #[serde(default)]
+    pub updated:   u64,
+}
+
+impl Status {
+    pub fn load_per_cpu(&self) -> f32 {
+        self.load / self.cpus.max(1) as f32
+    }
+}
+
 #[derive(Clone)]
 pub struct Data {
     home: PathBuf,
@@ -56,7 +111,7 @@ impl Data {
             match fs::create_dir(&path) {
                 Ok(_)  => return Ok(name),
                 Err(err) => {
-                    if let Err(_) = fs::metadata(&path) {
+                    if fs::metadata(&path).is_err() {
                         return Err(Error::DataError(format!("Failed to create {:?}: {}", path, err)));
                     }
                     // if path already exists, keep iterating
@@ -70,16 +125,30 @@ impl Data {
         self.home.join(name)
     }
 
+    pub fn list(&self) -> Result<Vec<String>, Error> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.home)? {
+            let entry = entry?;
+            if entry.metadata()?.is_dir() {
+                if let Ok(name) = entry.file_name().into_string() {
+                    names.push(name);
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
     pub fn read(&self, name: &str) -> Result<String, Error> {
-        let path = self.file(&name);
+        let path = self.file(name);
         fs::read_to_string(&path)
-            .or_else(|err| Err(Error::DataError(format!("Failed to read {:?}: {}", path, err))))
+            .map_err(|err| Error::DataError(format!("Failed to read {:?}: {}", path, err)))
     }
 
     pub fn write(&self, name: &str, data: &str) -> Result<(), Error> {
-        let path = self.file(&name);
-        fs::write(&path, &data)
-            .or_else(|err| Err(Error::DataError(format!("Failed to write {:?}: {}", path, err))))
+        let path = self.file(name);
+        fs::write(&path, data)
+            .map_err(|err| Error::DataError(format!("Failed to write {:?}: {}", path, err)))
     }
 }
 
@@ -103,19 +172,58 @@ impl Sower {
         format!("{}:8000", self.ip)
     }
 
-    pub fn ipxe(&self) -> Result<String, Error> {
-        let name = self.data.reserve("seed")?;
-        Ok(Seed::new(&self.data, &name)?.ipxe())
+    // Seeds are named after the first MAC address they boot from, so that
+    // the same hardware keeps its name and assignments across reboots
+    pub fn ipxe(&self, mac: &str) -> Result<String, Error> {
+        if !Regex::new(r"^([0-9a-f]{2}-){5}[0-9a-f]{2}$").unwrap().is_match(mac) {
+            return Err(Error::DataError(format!("Invalid MAC address {}", mac)));
+        }
+        let known = self.data.list()?.into_iter()
+            .filter(|name| name.starts_with("seed-"))
+            .find(|name| self.data.read(&format!("{}/mac", name)).ok().as_deref() == Some(mac));
+        let seed = match known {
+            Some(name) => Seed::new(&self.data, &name)?,
+            None => {
+                let seed = Seed::new(&self.data, &self.data.reserve("seed")?)?;
+                seed.data.write("mac", mac)?;
+                seed
+            },
+        };
+        Ok(seed.ipxe(&self.overlays(&seed)))
+    }
+
+    // overlay of the field, then overlays of the profiles named after the
+    // Seed labels in sorted order
+    fn overlays(&self, seed: &Seed) -> Vec<String> {
+        let mut labels: Vec<String> = seed.labels().into_iter().filter(|l| valid_label(l)).collect();
+        labels.sort();
+        Some(String::from("overlays/field.cpio")).into_iter()
+            .chain(labels.iter().map(|l| format!("overlays/profiles/{}.cpio", l)))
+            .filter(|o| self.data.file(o).is_file())
+            .collect()
+    }
+
+    /// Field overlay, or the overlay of a profile
+    pub fn overlay(&self, profile: Option<&str>) -> Result<PathBuf, Error> {
+        match profile {
+            None => Ok(self.data.file("overlays/field.cpio")),
+            Some(p) if valid_label(p) =>
+                Ok(self.data.file("overlays/profiles").join(format!("{}.cpio", p))),
+            Some(p) => Err(Error::DataError(format!("Invalid profile {}", p))),
+        }
     }
 
-    pub fn init(&self, name: &str) -> Result<String, Error> {
-        Seed::new(&self.data, &name)?.otp().map(|otp| {
-            format!("SOWER={}\nOTP={}\n", &self.ip, otp)
-        })
+    // last overlay, generated for every boot of the Seed
+    pub fn init(&self, name: &str) -> Result<Vec<u8>, Error> {
+        let otp = Seed::new(&self.data, name)?.otp()?;
+        let mut writer = cpio::Writer::new(Vec::new(), 0);
+        writer.append_file("etc/default/barley-seed", 0o600,
+            format!("SOWER={}\nOTP={}\n", &self.ip, otp).as_bytes())?;
+        writer.finish()
     }
 
     pub fn register(&self, name: &str, reg: &Registration) -> Result<Certs, Error> {
-        let seed = Seed::new(&self.data, &name)?;
+        let seed = Seed::new(&self.data, name)?;
         seed.check_otp(&reg.otp)?;
         seed.write_ip(&reg.ip)?;
         let admin = ssh::authorized_keys(&self.data.file("admin.pub"))?;
@@ -127,7 +235,115 @@ impl Sower {
             &self.data.file("machine.key"),
         )?;
         cert.push_str(&self.data.read("machine.crt")?);
-        Ok(Certs { admin, host, ca, cert })
+        if let Ok(cross) = self.data.read("cross.crt") {
+            // root.crt has been rotated, let seeds that only trust the
+            // previous root build a path through the cross-signed cert
+            cert.push_str(&cross);
+        }
+        let token = seed.issue_token()?;
+        Ok(Certs { admin, host, ca, cert, token })
+    }
+
+    pub fn admin(&self, name: &str, token: &str) -> Result<String, Error> {
+        Seed::new(&self.data, name)?.check_token(token)?;
+        ssh::authorized_keys(&self.data.file("admin.pub"))
+    }
+
+    pub fn seeds(&self) -> Result<Vec<SeedInfo>, Error> {
+        Ok(self.data.list()?.iter()
+            .filter(|name| name.starts_with("seed-"))
+            .filter_map(|name| Seed::new(&self.data, name).ok()?.info())
+            .collect())
+    }
+
+    pub fn status(&self, name: &str, token: &str, status: &Status) -> Result<(), Error> {
+        let seed = Seed::new(&self.data, name)?;
+        seed.check_token(token)?;
+        seed.write_status(status)
+    }
+
+    pub fn label(&self, name: &str, add: &[String], remove: &[String]) -> Result<(), Error> {
+        let seed = Seed::new(&self.data, name)?;
+        if seed.info().is_none() {
+            return Err(Error::DataError(format!("Seed {} has not registered", &name)));
+        }
+        if let Some(l) = add.iter().chain(remove.iter()).find(|l| !valid_label(l)) {
+            return Err(Error::DataError(format!("Invalid label {}", l)));
+        }
+        let mut labels: Vec<String> = seed.labels().into_iter()
+            .filter(|l| !remove.contains(l))
+            .collect();
+        for l in add {
+            if !labels.contains(l) {
+                labels.push(l.to_string());
+            }
+        }
+        seed.data.write("labels", &labels.iter().map(|l| format!("{}\n", l)).collect::<String>())
+    }
+
+    pub fn assign(&self, name: &str, assignment: &Assignment) -> Result<(), Error> {
+        let seed = Seed::new(&self.data, name)?;
+        seed.machines()?.write(&assignment_file(&assignment.name)?, &serde_json::to_string(&assignment)
+            .map_err(|err| Error::DataError(err.to_string()))?)
+    }
+
+    pub fn unassign(&self, name: &str, machine: &str) -> Result<(), Error> {
+        let path = Seed::new(&self.data, name)?.machines()?.file(&assignment_file(machine)?);
+        if path.is_file() {
+            fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+
+    pub fn assignments(&self, name: &str, token: &str) -> Result<Vec<Assignment>, Error> {
+        let seed = Seed::new(&self.data, name)?;
+        seed.check_token(token)?;
+        seed.assignments()
+    }
+
+    // images are pushed by sow, any registered Seed can pull them
+    fn authenticate(&self, token: &str) -> Result<Seed, Error> {
+        self.data.list()?.iter()
+            .filter(|name| name.starts_with("seed-"))
+            .filter_map(|name| Seed::new(&self.data, name).ok())
+            .find(|seed| !token.is_empty() && seed.data.read("token").ok().as_deref() == Some(token))
+            .ok_or(Error::TokenError())
+    }
+
+    fn image_file(&self, name: &str, version: &str, extension: &str) -> Result<PathBuf, Error> {
+        if !Regex::new(r"^[[:alnum:]][[:alnum:].+-]*$").unwrap().is_match(name)
+            || !Regex::new(r"^[[:alnum:]][[:alnum:]_.+~-]*$").unwrap().is_match(version) {
+            return Err(Error::DataError(format!("Invalid image {} {}", name, version)));
+        }
+        Ok(self.data.file("images").join(format!("{}_{}.{}", name, version, extension)))
+    }
+
+    pub fn image(&self, token: &str, name: &str, version: &str) -> Result<PathBuf, Error> {
+        self.authenticate(token)?;
+        self.image_file(name, version, "tar.zst")
+    }
+
+    pub fn signature(&self, token: &str, name: &str, version: &str) -> Result<PathBuf, Error> {
+        self.authenticate(token)?;
+        self.image_file(name, version, "tar.zst.sig")
+    }
+
+    // empty when the field doesn't require signed images
+    pub fn build_keys(&self, token: &str) -> Result<String, Error> {
+        self.authenticate(token)?;
+        Ok(self.data.read("build_keys").unwrap_or_default())
+    }
+
+    pub fn checksum(&self, token: &str, name: &str, version: &str) -> Result<String, Error> {
+        self.authenticate(token)?;
+        let path = self.image_file(name, version, "sha256")?;
+        fs::read_to_string(&path)
+            .map_err(|err| Error::DataError(format!("Failed to read {:?}: {}", path, err)))
+    }
+
+    pub fn krl(&self, name: &str, token: &str) -> Result<PathBuf, Error> {
+        Seed::new(&self.data, name)?.check_token(token)?;
+        Ok(self.data.file("revoked.krl"))
     }
 
     fn parse_dnsmasq(conf: &str) -> net::IpAddr {
@@ -143,7 +359,7 @@ impl Sower {
     }
 
     fn detect_bind_ip(dnsmasq: &str) -> net::IpAddr {
-        match fs::read_to_string(&dnsmasq) {
+        match fs::read_to_string(dnsmasq) {
             Ok(conf) => Self::parse_dnsmasq(&conf),
             Err(err) => panic!("Failed to read {}: {}", dnsmasq, err),
         }
@@ -159,21 +375,25 @@ impl Seed {
     pub fn new(home: &Data, name: &str) -> Result<Self, Error> {
         Ok(Seed {
             name: name.to_string(),
-            data: Data::new(home.file(&name))?
+            data: Data::new(home.file(name))?
         })
     }
 
-    pub fn ipxe(&self) -> String {
+    // overlays are unpacked in order on top of the Seed image, later files
+    // replace earlier ones. The script is chained from /seed.ipxe, so paths
+    // are absolute or iPXE would look for them under /seed/.
+    pub fn ipxe(&self, overlays: &[String]) -> String {
         if let Err(err) = self.data.write("otp", &random_pw()) {
             eprintln!("Failed to write to {:?}: {}", self.data.file("otp"), err);
             // complain but let it boot anyway
         }
+        let overlays: String = overlays.iter().map(|o| format!("initrd /{}\n", o)).collect();
         format!(r"#!ipxe
-kernel seed.vmlinuz rdinit=/lib/systemd/systemd systemd.hostname={} console=ttyS0
-initrd seed.cpio.zst
-initrd init/{} /etc/default/barley-seed
+kernel /seed.vmlinuz rdinit=/lib/systemd/systemd systemd.hostname={} console=ttyS0
+initrd /seed.cpio.zst
+{}initrd /init/{}.cpio
 boot
-", self.name, self.name)
+", self.name, overlays, self.name)
     }
 
     pub fn otp(&self) -> Result<String, Error> {
@@ -189,13 +409,66 @@ boot
         Ok(())
     }
 
+    pub fn issue_token(&self) -> Result<String, Error> {
+        let token = random_pw();
+        self.data.write("token", &token)?;
+        Ok(token)
+    }
+
+    pub fn check_token(&self, token: &str) -> Result<(), Error> {
+        match self.data.read("token") {
+            Ok(t) if !token.is_empty() && t == token => Ok(()),
+            _ => {
+                eprintln!("Token mismatch for {}", &self.name);
+                Err(Error::TokenError())
+            },
+        }
+    }
+
+    // None until the Seed has registered
+    pub fn info(&self) -> Option<SeedInfo> {
+        let ip = self.data.read("ip").ok()?;
+        let status = self.data.read("status").ok()
+            .and_then(|s| serde_json::from_str(&s).ok());
+        Some(SeedInfo { name: self.name.to_string(), ip, labels: self.labels(), status })
+    }
+
+    pub fn labels(&self) -> Vec<String> {
+        self.data.read("labels").unwrap_or_default()
+            .lines().map(|l| l.to_string()).collect()
+    }
+
+    fn machines(&self) -> Result<Data, Error> {
+        Data::new(self.data.file("machines"))
+    }
+
+    pub fn assignments(&self) -> Result<Vec<Assignment>, Error> {
+        let machines = self.machines()?;
+        let mut files: Vec<PathBuf> = fs::read_dir(&machines.home)?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.extension().map(|e| e == "json").unwrap_or(false))
+            .collect();
+        files.sort();
+        files.iter()
+            .map(|path| serde_json::from_str(&fs::read_to_string(path)?)
+                .map_err(|err| Error::DataError(format!("Failed to parse {:?}: {}", path, err))))
+            .collect()
+    }
+
+    pub fn write_status(&self, status: &Status) -> Result<(), Error> {
+        let mut status = status.clone();
+        status.updated = now();
+        self.data.write("status", &serde_json::to_string(&status)
+            .map_err(|err| Error::DataError(err.to_string()))?)
+    }
+
     pub fn write_ip(&self, ip: &net::IpAddr) -> Result<(), Error> {
         self.data.write("ip", &ip.to_string())
     }
 
     fn sign_ssh(&self, key: &str, ca: &PathBuf) -> Result<String, Error> {
         self.data.write("ssh.pub", key)?;
-        ssh::sign(&self.name, &ca, &self.data.file("ssh.pub"))?;
+        ssh::sign(&self.name, ca, &self.data.file("ssh.pub"))?;
         self.data.read("ssh-cert.pub")
     }
 
@@ -203,8 +476,8 @@ boot
         fs::write(self.data.file("csr"), csr)?;
         tls::sign(
             &self.name,
-            &cacert,
-            &cakey,
+            cacert,
+            cakey,
             &self.data.file("csr"),
             &self.data.file("crt"),
         )?;
@@ -216,6 +489,12 @@ pub struct NameCounter {
     count: [u8; 8],
 }
 
+impl Default for NameCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl NameCounter {
     pub fn new() -> Self {
         NameCounter { count: [b'0'; 8] }
@@ -241,6 +520,64 @@ impl Iterator for NameCounter {
     }
 }
 
+/// Seed labels end up in shell commands, iPXE scripts, and overlay paths
+pub fn valid_label(label: &str) -> bool {
+    Regex::new(r"^[[:alnum:]_][[:alnum:]_.=-]*$").unwrap().is_match(label)
+}
+
+// the machine name ends up in a path on the Sower
+fn assignment_file(machine: &str) -> Result<String, Error> {
+    match Regex::new(r"^[[:alnum:]][[:alnum:]_.-]*$").unwrap().is_match(machine) {
+        true  => Ok(format!("{}.json", machine)),
+        false => Err(Error::DataError(format!("Invalid machine name {}", machine))),
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+const SIZE_UNITS: &[(char, u64)] = &[('T', 1 << 40), ('G', 1 << 30), ('M', 1 << 20), ('K', 1 << 10)];
+
+pub fn parse_size(size: &str) -> Result<u64, Error> {
+    let size = size.trim();
+    let (number, unit) = match size.chars().last() {
+        Some(c) if c.is_ascii_alphabetic() => {
+            let unit = SIZE_UNITS.iter()
+                .find(|(u, _)| *u == c.to_ascii_uppercase())
+                .ok_or(format!("Unknown size unit in '{}'", size))?;
+            (&size[..size.len() - 1], unit.1)
+        },
+        _ => (size, 1),
+    };
+    number.parse::<u64>()
+        .map(|n| n * unit)
+        .map_err(|_| Error::from(format!("Invalid size '{}'", size)))
+}
+
+const DURATION_UNITS: &[(char, u64)] = &[('w', 604800), ('d', 86400), ('h', 3600), ('m', 60), ('s', 1)];
+
+/// Seconds in a duration like 90d or 12h
+pub fn parse_duration(duration: &str) -> Result<u64, Error> {
+    let duration = duration.trim();
+    let invalid = || Error::from(format!("Invalid duration '{}', use e.g. 30d or 12h", duration));
+    let unit = duration.chars().last()
+        .and_then(|c| DURATION_UNITS.iter().find(|(u, _)| *u == c))
+        .ok_or_else(invalid)?;
+    duration[..duration.len() - 1].parse::<u64>()
+        .map(|n| n * unit.1)
+        .map_err(|_| invalid())
+}
+
+pub fn human_size(size: u64) -> String {
+    for (unit, bytes) in SIZE_UNITS {
+        if size >= *bytes {
+            return format!("{:.1}{}", size as f64 / *bytes as f64, unit);
+        }
+    }
+    size.to_string()
+}
+
 pub fn random_pw() -> String {
     let pw: u128 = random();
     format!("{:0x}", pw)
@@ -260,7 +597,7 @@ where F: Fn(&'a T) -> Vec<&'a str> {
     }
     let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
     for record in list {
-        let f = &fields(&record);
+        let f = &fields(record);
         for i in 0..widths.len()-1 {
             if i > f.len()-1 {
                 break;
@@ -270,9 +607,9 @@ where F: Fn(&'a T) -> Vec<&'a str> {
             }
         }
     }
-    println!("{}", table_line(&headers, &widths));
+    println!("{}", table_line(headers, &widths));
     for record in list {
-        println!("{}", table_line(&fields(&record), &widths));
+        println!("{}", table_line(&fields(record), &widths));
     }
 }
 
@@ -285,6 +622,7 @@ pub enum Error {
     IoError(String),
     OtpError(),
     StrError(String),
+    TokenError(),
 }
 
 impl Display for Error {
@@ -358,12 +696,93 @@ mod tests {
         assert_eq!(c.next(), Some(String::from("2be")));
     }
 
+    #[test]
+    fn test_size() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("4G").unwrap(), 4 << 30);
+        assert_eq!(parse_size("16m").unwrap(), 16 << 20);
+        assert!(parse_size("4X").is_err());
+        assert_eq!(human_size(1536 << 20), "1.5G");
+        assert_eq!(human_size(100), "100");
+    }
+
+    #[test]
+    fn test_duration() {
+        assert_eq!(parse_duration("90d").unwrap(), 90 * 86400);
+        assert_eq!(parse_duration("2w").unwrap(), 14 * 86400);
+        assert_eq!(parse_duration("12h").unwrap(), 43200);
+        assert!(parse_duration("30").is_err());
+        assert!(parse_duration("d").is_err());
+    }
+
     #[test]
     fn test_data() {
         let data = Data::new(PathBuf::from("/tmp")).unwrap();
         assert_eq!(data.file("foo"), PathBuf::from("/tmp/foo"));
     }
 
+    #[test]
+    fn test_assignments() {
+        let home = PathBuf::from(format!("/tmp/barley-test-{}", random_pw()));
+        let data = Data::new(home.clone()).unwrap();
+        let seed = Seed::new(&data, "seed-1").unwrap();
+        seed.issue_token().unwrap();
+        let sower = Sower { ip: "127.0.0.1".parse().unwrap(), images: data.clone(), data };
+        let assignment = |name: &str| Assignment {
+            name: name.to_string(),
+            image: String::from("postgres_20261001"),
+            network: None,
+            volumes: vec![String::from("/var/lib/postgresql:postgres")],
+            stopped: false,
+        };
+        sower.assign("seed-1", &assignment("postgres-2")).unwrap();
+        sower.assign("seed-1", &assignment("postgres-1")).unwrap();
+        assert!(sower.assign("seed-1", &assignment("../token")).is_err());
+        let names: Vec<String> = seed.assignments().unwrap().into_iter().map(|a| a.name).collect();
+        assert_eq!(names, vec!["postgres-1", "postgres-2"]);
+        sower.unassign("seed-1", "postgres-2").unwrap();
+        assert!(sower.unassign("seed-1", "../token").is_err());
+        assert!(seed.data.file("token").is_file());
+        assert_eq!(seed.assignments().unwrap().len(), 1);
+        assert!(sower.assignments("seed-1", "").is_err());
+        let token = seed.data.read("token").unwrap();
+        assert_eq!(sower.image(&token, "postgres", "20261001").unwrap(),
+            home.join("images/postgres_20261001.tar.zst"));
+        assert!(sower.image(&token, "..", "token").is_err());
+        assert!(sower.image("", "postgres", "20261001").is_err());
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn test_overlays() {
+        let home = PathBuf::from(format!("/tmp/barley-test-{}", random_pw()));
+        let data = Data::new(home.clone()).unwrap();
+        let seed = Seed::new(&data, "seed-1").unwrap();
+        seed.data.write("labels", "zone=b\nrole=storage\ngpu\n").unwrap();
+        let sower = Sower { ip: "127.0.0.1".parse().unwrap(), images: data.clone(), data };
+        assert!(sower.overlays(&seed).is_empty());
+        fs::create_dir_all(home.join("overlays/profiles")).unwrap();
+        for overlay in &["field", "profiles/zone=b", "profiles/role=storage", "profiles/zone=a"] {
+            fs::write(home.join(format!("overlays/{}.cpio", overlay)), "").unwrap();
+        }
+        assert_eq!(seed.ipxe(&sower.overlays(&seed)), "#!ipxe\n\
+            kernel /seed.vmlinuz rdinit=/lib/systemd/systemd systemd.hostname=seed-1 console=ttyS0\n\
+            initrd /seed.cpio.zst\n\
+            initrd /overlays/field.cpio\n\
+            initrd /overlays/profiles/role=storage.cpio\n\
+            initrd /overlays/profiles/zone=b.cpio\n\
+            initrd /init/seed-1.cpio\n\
+            boot\n");
+        assert_eq!(sower.overlay(Some("gpu")).unwrap(), home.join("overlays/profiles/gpu.cpio"));
+        assert!(sower.overlay(Some("../field")).is_err());
+        assert!(valid_label("zone=b") && !valid_label("-x") && !valid_label("a b") && !valid_label("a;b"));
+        let init = sower.init("seed-1").unwrap();
+        assert_eq!(init.len(), 512);
+        let otp = seed.otp().unwrap();
+        assert!(String::from_utf8_lossy(&init).contains(&format!("OTP={}\n", otp)));
+        fs::remove_dir_all(&home).unwrap();
+    }
+
     #[test]
     fn test_parse_dnsmasq() {
         let ip = Sower::parse_dnsmasq("pxe-service=net:ipxe, X86PC,, http://127.0.0.1:8000/seed.ipxe");
@@ -1,8 +1,9 @@
 use actix_files::NamedFile;
-use actix_web::{get, App, HttpResponse, HttpServer, middleware, post, Result, web};
-use std::env;
+use actix_web::{get, App, HttpRequest, HttpResponse, HttpServer, middleware, post, Result, web};
+use std::{env, io};
+use structopt::StructOpt;
 
-use barley::{Error, Registration, Sower};
+use barley::{Assignment, Error, Registration, Sower, Status};
 
 const DNSMASQ: &str = "/etc/dnsmasq.d/barley.conf";
 const IMAGE_DIR: &str = "/srv/barley";
@@ -18,12 +19,34 @@ async fn cpio(sower: web::Data<Sower>) -> Result<NamedFile> {
     Ok(NamedFile::open(sower.images.file("seed.cpio.zst"))?)
 }
 
+// chain to a script that is specific to the booting hardware
 #[get("/seed.ipxe")]
-async fn ipxe(sower: web::Data<Sower>) -> Result<HttpResponse, Error> {
-    Ok(HttpResponse::Ok().body(sower.ipxe()?))
+async fn chain() -> HttpResponse {
+    HttpResponse::Ok().body("#!ipxe\nchain seed/${netX/mac:hexhyp}.ipxe\n")
 }
 
-#[get("/init/{name}")]
+#[get("/seed/{mac}.ipxe")]
+async fn ipxe(
+    sower:          web::Data<Sower>,
+    web::Path(mac): web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().body(sower.ipxe(&mac)?))
+}
+
+#[get("/overlays/field.cpio")]
+async fn field_overlay(sower: web::Data<Sower>) -> Result<NamedFile> {
+    Ok(NamedFile::open(sower.overlay(None)?)?)
+}
+
+#[get("/overlays/profiles/{profile}.cpio")]
+async fn profile_overlay(
+    sower:              web::Data<Sower>,
+    web::Path(profile): web::Path<String>,
+) -> Result<NamedFile> {
+    Ok(NamedFile::open(sower.overlay(Some(&profile))?)?)
+}
+
+#[get("/init/{name}.cpio")]
 async fn init(
     sower:           web::Data<Sower>,
     web::Path(name): web::Path<String>,
@@ -46,12 +69,155 @@ async fn register(
     }
 }
 
+fn token(req: &HttpRequest) -> &str {
+    req.headers().get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .unwrap_or("")
+}
+
+#[get("/admin/{name}")]
+async fn admin(
+    sower:           web::Data<Sower>,
+    web::Path(name): web::Path<String>,
+    req:             HttpRequest,
+) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().body(sower.admin(&name, token(&req))?))
+}
+
+#[post("/status/{name}")]
+async fn status(
+    sower:           web::Data<Sower>,
+    web::Path(name): web::Path<String>,
+    req:             HttpRequest,
+    status:          web::Json<Status>,
+) -> Result<HttpResponse, Error> {
+    sower.status(&name, token(&req), &status)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[get("/machines/{name}")]
+async fn machines(
+    sower:           web::Data<Sower>,
+    web::Path(name): web::Path<String>,
+    req:             HttpRequest,
+) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(sower.assignments(&name, token(&req))?))
+}
+
+#[get("/images/{name}/{version}")]
+async fn image(
+    sower:                      web::Data<Sower>,
+    web::Path((name, version)): web::Path<(String, String)>,
+    req:                        HttpRequest,
+) -> Result<NamedFile> {
+    Ok(NamedFile::open(sower.image(token(&req), &name, &version)?)?)
+}
+
+#[get("/images/{name}/{version}/sha256")]
+async fn checksum(
+    sower:                      web::Data<Sower>,
+    web::Path((name, version)): web::Path<(String, String)>,
+    req:                        HttpRequest,
+) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().body(sower.checksum(token(&req), &name, &version)?))
+}
+
+#[get("/images/{name}/{version}/sig")]
+async fn signature(
+    sower:                      web::Data<Sower>,
+    web::Path((name, version)): web::Path<(String, String)>,
+    req:                        HttpRequest,
+) -> Result<NamedFile> {
+    Ok(NamedFile::open(sower.signature(token(&req), &name, &version)?)?)
+}
+
+#[get("/build-keys")]
+async fn build_keys(sower: web::Data<Sower>, req: HttpRequest) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().body(sower.build_keys(token(&req))?))
+}
+
+#[get("/krl/{name}")]
+async fn krl(
+    sower:           web::Data<Sower>,
+    web::Path(name): web::Path<String>,
+    req:             HttpRequest,
+) -> Result<NamedFile> {
+    Ok(NamedFile::open(sower.krl(&name, token(&req))?)?)
+}
+
+/// Barley Sower web server
+#[derive(StructOpt)]
+struct Opt {
+    #[structopt(subcommand)]
+    op: Option<Op>
+}
+
+#[derive(StructOpt)]
+enum Op {
+    /// Serve Seed images and registration API (default)
+    Serve,
+
+    /// Print registered Seeds as JSON
+    Seeds,
+
+    /// Add or remove Seed labels
+    Label {
+        /// Seed name
+        seed: String,
+        /// Labels to be added
+        labels: Vec<String>,
+        /// Labels to be removed
+        #[structopt(long, number_of_values = 1)]
+        rm: Vec<String>,
+    },
+
+    /// Keep a machine running on a Seed, reads the assignment as JSON from stdin
+    Assign {
+        /// Seed name
+        seed: String,
+    },
+
+    /// Stop keeping a machine running on a Seed
+    Unassign {
+        /// Seed name
+        seed: String,
+        /// Machine name
+        machine: String,
+    },
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let opt = Opt::from_args();
+    let sower = Sower::new(DNSMASQ, IMAGE_DIR, DATA_DIR);
+    match opt.op {
+        None | Some(Op::Serve) => { serve(sower).await },
+        Some(Op::Seeds) => {
+            let seeds = sower.seeds().expect("Failed to list Seeds");
+            println!("{}", serde_json::to_string(&seeds)?);
+            Ok(())
+        },
+        Some(Op::Label { seed, labels, rm }) => {
+            sower.label(&seed, &labels, &rm).expect("Failed to update Seed labels");
+            Ok(())
+        },
+        Some(Op::Assign { seed }) => {
+            let assignment: Assignment = serde_json::from_reader(io::stdin())?;
+            sower.assign(&seed, &assignment).expect("Failed to assign machine");
+            Ok(())
+        },
+        Some(Op::Unassign { seed, machine }) => {
+            sower.unassign(&seed, &machine).expect("Failed to unassign machine");
+            Ok(())
+        },
+    }
+}
+
+async fn serve(sower: Sower) -> std::io::Result<()> {
     env::set_var("RUST_LOG", "actix_web=debug,actix_server=info");
     env_logger::init();
 
-    let sower = Sower::new(DNSMASQ, IMAGE_DIR, DATA_DIR);
     let binding = sower.binding();
 
     HttpServer::new(move || {
@@ -60,9 +226,20 @@ async fn main() -> std::io::Result<()> {
             .data(sower.clone())
             .service(vmlinuz)
             .service(cpio)
+            .service(chain)
             .service(ipxe)
+            .service(field_overlay)
+            .service(profile_overlay)
             .service(init)
             .service(register)
+            .service(admin)
+            .service(krl)
+            .service(machines)
+            .service(image)
+            .service(checksum)
+            .service(signature)
+            .service(build_keys)
+            .service(status)
     })
     .bind(binding)?
     .run()
@@ -0,0 +1,463 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::{Error, parse_size};
+use crate::schedule::Requirements;
+
+/// Desired state of the machines in a field
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default)]
+    pub machines: BTreeMap<String, MachineSpec>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MachineSpec {
+    pub image: String,
+    /// Latest imported version when not set
+    pub version: Option<String>,
+    pub seed: Option<String>,
+    #[serde(default)]
+    pub local: bool,
+    pub policy: Option<String>,
+    pub memory: Option<String>,
+    #[serde(default)]
+    pub require: Vec<String>,
+    #[serde(default)]
+    pub prefer: Vec<String>,
+    pub anti_affinity: Option<String>,
+    pub network: Option<Vec<String>>,
+    #[serde(default)]
+    pub volumes: Vec<String>,
+    #[serde(default)]
+    pub ca: bool,
+    #[serde(default)]
+    pub stopped: bool,
+}
+
+impl MachineSpec {
+    pub fn policy(&self) -> &str {
+        self.policy.as_deref().unwrap_or("least-loaded")
+    }
+
+    pub fn requirements(&self) -> Result<Requirements, Error> {
+        Ok(Requirements {
+            memory: parse_size(self.memory.as_deref().unwrap_or("512M"))?,
+            labels: self.require.clone(),
+            prefer: self.prefer.clone(),
+            ..Default::default()
+        })
+    }
+
+    pub fn volumes(&self) -> Result<Vec<Volume>, Error> {
+        self.volumes.iter().map(|v| Volume::parse(v)).collect()
+    }
+}
+
+impl Manifest {
+    pub fn parse(manifest: &str) -> Result<Self, Error> {
+        let manifest: Manifest = toml::from_str(manifest)
+            .map_err(|err| Error::ConfError(err.to_string()))?;
+        let valid = Regex::new(r"^[[:alnum:]][[:alnum:]_.-]*$").unwrap();
+        for (name, spec) in manifest.machines.iter() {
+            if !valid.is_match(name) {
+                return Err(Error::ConfError(format!("Invalid machine name '{}'", name)));
+            }
+            if spec.local && spec.seed.is_some() {
+                return Err(Error::ConfError(format!("Machine '{}' has both seed and local", name)));
+            }
+            spec.requirements()?;
+            spec.volumes()?;
+        }
+        Ok(manifest)
+    }
+
+    pub fn load(path: &PathBuf) -> Result<Self, Error> {
+        let manifest = fs::read_to_string(path)
+            .map_err(|err| Error::ConfError(format!("Failed to read {:?}: {}", path, err)))?;
+        Self::parse(&manifest)
+    }
+}
+
+/// Persistent volume attached with attach-disk, "path:owner[:size]"
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Volume {
+    pub path: String,
+    pub owner: String,
+    pub size: Option<String>,
+}
+
+impl Volume {
+    pub fn parse(spec: &str) -> Result<Self, Error> {
+        let invalid = || Error::ConfError(format!("Invalid volume '{}', use path:owner[:size]", spec));
+        let f: Vec<&str> = spec.split(':').collect();
+        if f.len() < 2 || f.len() > 3 {
+            return Err(invalid());
+        }
+        let path = Regex::new(r"^(/[[:alnum:]_.-]+)+$").unwrap();
+        let owner = Regex::new(r"^[[:alnum:]_][[:alnum:]_.-]*$").unwrap();
+        if !path.is_match(f[0]) || !owner.is_match(f[1]) {
+            return Err(invalid());
+        }
+        if let Some(size) = f.get(2) {
+            parse_size(size)?;
+        }
+        Ok(Volume {
+            path: f[0].to_string(),
+            owner: f[1].to_string(),
+            size: f.get(2).map(|s| s.to_string()),
+        })
+    }
+
+    /// Size of the logical volume, attach-disk creates 4G ones by default
+    pub fn bytes(&self) -> u64 {
+        parse_size(self.size.as_deref().unwrap_or("4G")).unwrap_or(0)
+    }
+
+    /// Logical volume name, e.g. postgres-1-var-lib-postgresql
+    pub fn lv(&self, machine: &str) -> String {
+        format!("{}{}", machine, self.path.replace('/', "-"))
+    }
+
+    pub fn script(&self, machine: &str) -> String {
+        let size = match &self.size {
+            Some(s) => format!("SIZE={} ", s),
+            None    => String::new(),
+        };
+        format!("{}attach-disk {} {} {} {}", size, machine, self.path, self.owner, self.lv(machine))
+    }
+}
+
+impl Display for Volume {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.path, self.owner)?;
+        if let Some(size) = &self.size {
+            write!(f, ":{}", size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Machine known to the field, as seen on its Seed
+pub struct Current {
+    pub name: String,
+    pub image: String,
+    pub version: String,
+    pub seed: Option<String>,
+    pub running: bool,
+    /// Volumes stay on the Seed, so machines with volumes can't be moved
+    pub volumes: Vec<Volume>,
+    pub network: Option<Vec<String>>,
+    pub ca: bool,
+    /// Anti-affinity group
+    pub group: Option<String>,
+    /// Labels of the Seed, None when they are unknown
+    pub labels: Option<Vec<String>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Create { name: String, image: String, version: String },
+    Start { name: String },
+    Stop { name: String },
+    Upgrade { name: String, image: String, from: String, to: String },
+    Move { name: String, from: String, to: String },
+    /// Same image with a new network, volumes, CA, or anti-affinity group
+    Replace { name: String, changes: String },
+}
+
+impl Action {
+    pub fn name(&self) -> &str {
+        match self {
+            Action::Create { name, .. } => name,
+            Action::Start { name }      => name,
+            Action::Stop { name }       => name,
+            Action::Upgrade { name, .. } => name,
+            Action::Move { name, .. }   => name,
+            Action::Replace { name, .. } => name,
+        }
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Create { name, image, version } =>
+                write!(f, "create {} from {} {}", name, image, version),
+            Action::Start { name } => write!(f, "start {}", name),
+            Action::Stop { name } => write!(f, "stop {}", name),
+            Action::Upgrade { name, image, from, to } =>
+                write!(f, "upgrade {} from {} {} to {}", name, image, from, to),
+            Action::Move { name, from, to } => write!(f, "move {} from {} to {}", name, from, to),
+            Action::Replace { name, changes } => write!(f, "replace {} for {}", name, changes),
+        }
+    }
+}
+
+/// Actions that bring current machines in line with the manifest, machines
+/// that are not in the manifest are left alone.
+pub fn plan<F>(manifest: &Manifest, current: &[Current], latest: F) -> Result<Vec<Action>, Error>
+where F: Fn(&str) -> Option<String> {
+    let mut actions = Vec::new();
+    for (name, spec) in manifest.machines.iter() {
+        let version = match &spec.version {
+            Some(v) => v.to_string(),
+            None    => latest(&spec.image).ok_or(format!(
+                "No images found for '{}'. Run 'sow import <path>'.", &spec.image))?,
+        };
+        let name = name.to_string();
+        let machine = match current.iter().find(|m| m.name == name) {
+            Some(m) => m,
+            None => {
+                if !spec.stopped {
+                    actions.push(Action::Create { name, image: spec.image.to_string(), version });
+                }
+                continue;
+            },
+        };
+        let seed = match spec.local {
+            true  => None,
+            false => spec.seed.clone().or(machine.seed.clone()),
+        };
+        // Seeds picked by the scheduler have to keep meeting the requirements,
+        // of two machines from the same group on one Seed the first one stays
+        let group = |m: &Current| match manifest.machines.get(&m.name) {
+            Some(spec) => spec.anti_affinity.clone(),
+            None       => m.group.clone(),
+        };
+        let misplaced = spec.seed.is_none() && machine.seed.is_some() && (
+            machine.labels.as_ref().map(|l| spec.require.iter().any(|r| !l.contains(r))).unwrap_or(false)
+            || (spec.anti_affinity.is_some() && current.iter().any(|m|
+                m.name < machine.name && m.seed == machine.seed && group(m) == spec.anti_affinity)));
+        let mut changes = Vec::new();
+        if spec.network.is_some() && spec.network != machine.network {
+            changes.push("network");
+        }
+        if !spec.volumes.is_empty() && spec.volumes()? != machine.volumes {
+            changes.push("volumes");
+        }
+        if spec.ca != machine.ca {
+            changes.push("ca");
+        }
+        if spec.anti_affinity != machine.group {
+            changes.push("anti-affinity");
+        }
+        let place = |s: &Option<String>| s.clone().unwrap_or(String::from("local"));
+        let replaced = if seed != machine.seed || misplaced {
+            if !machine.volumes.is_empty() {
+                return Err(Error::from(format!(
+                    "Machine {} has volumes on {}, they would not move along. Move the data \
+                     and remove the machine with 'sow rm {}' first.", &name, place(&machine.seed), &name)));
+            }
+            let to = match misplaced {
+                true  => String::from("another Seed"),
+                false => place(&seed),
+            };
+            actions.push(Action::Move { name: name.to_string(), from: place(&machine.seed), to });
+            true
+        } else if spec.image != machine.image || version != machine.version {
+            // the new image starts with the network, volumes, and CA of the spec
+            actions.push(Action::Upgrade {
+                name: name.to_string(),
+                image: spec.image.to_string(),
+                from: machine.version.to_string(),
+                to: version,
+            });
+            true
+        } else if !changes.is_empty() {
+            actions.push(Action::Replace { name: name.to_string(), changes: changes.join(", ") });
+            true
+        } else {
+            false
+        };
+        // moved, upgraded, and replaced machines are started
+        let running = replaced || machine.running;
+        if spec.stopped && running {
+            actions.push(Action::Stop { name });
+        } else if !spec.stopped && !running {
+            actions.push(Action::Start { name });
+        }
+    }
+    Ok(actions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"
+[machines.postgres-1]
+image = "postgres"
+seed = "seed-1"
+memory = "2G"
+require = ["ssd"]
+anti_affinity = "db"
+volumes = ["/var/lib/postgresql:postgres:32G"]
+
+[machines.web-1]
+image = "web"
+version = "20261001"
+network = ["Bridge=br1"]
+
+[machines.web-2]
+image = "web"
+version = "20261001"
+
+[machines.batch-1]
+image = "batch"
+stopped = true
+"#;
+
+    fn current(name: &str, image: &str, version: &str, seed: &str, running: bool) -> Current {
+        Current {
+            name: name.to_string(),
+            image: image.to_string(),
+            version: version.to_string(),
+            seed: Some(seed.to_string()),
+            running,
+            volumes: Vec::new(),
+            network: None,
+            ca: false,
+            group: None,
+            labels: None,
+        }
+    }
+
+    #[test]
+    fn test_manifest() {
+        let m = Manifest::parse(MANIFEST).unwrap();
+        assert_eq!(m.machines.len(), 4);
+        let pg = &m.machines["postgres-1"];
+        assert_eq!(pg.requirements().unwrap().memory, 2 << 30);
+        assert_eq!(pg.policy(), "least-loaded");
+        assert_eq!(pg.volumes().unwrap()[0].script("postgres-1"),
+            "SIZE=32G attach-disk postgres-1 /var/lib/postgresql postgres postgres-1-var-lib-postgresql");
+        assert!(Manifest::parse("[machines.x]\nimage = \"x\"\ncolor = \"red\"\n").is_err());
+        assert!(Manifest::parse("[machines.x]\nimage = \"x\"\nseed = \"s\"\nlocal = true\n").is_err());
+        assert!(Manifest::parse("[machines.x]\nimage = \"x\"\nvolumes = [\"/data\"]\n").is_err());
+    }
+
+    #[test]
+    fn test_volume() {
+        let v = Volume::parse("/var/lib/app:app").unwrap();
+        assert_eq!(v.size, None);
+        assert_eq!(v.to_string(), "/var/lib/app:app");
+        assert_eq!(v.script("app-1"), "attach-disk app-1 /var/lib/app app app-1-var-lib-app");
+        assert_eq!(v.bytes(), 4 << 30);
+        assert_eq!(Volume::parse("/var/lib/app:app:32G").unwrap().bytes(), 32 << 30);
+        assert!(Volume::parse("/data:app:lots").is_err());
+        assert!(Volume::parse("data:app").is_err());
+        assert!(Volume::parse("/data;reboot:app").is_err());
+    }
+
+    #[test]
+    fn test_plan() {
+        let m = Manifest::parse(MANIFEST).unwrap();
+        let latest = |image: &str| match image {
+            "postgres" => Some(String::from("20261002")),
+            "batch"    => Some(String::from("1")),
+            _          => None,
+        };
+        let mut current = vec![
+            current("postgres-1", "postgres", "20260901", "seed-1", true),
+            current("web-1", "web", "20261001", "seed-2", false),
+            current("web-2", "web", "20261001", "seed-2", true),
+            current("batch-1", "batch", "1", "seed-3", true),
+            current("other-1", "other", "1", "seed-3", true),
+        ];
+        current[0].volumes = m.machines["postgres-1"].volumes().unwrap();
+        current[0].group = Some(String::from("db"));
+        current[1].network = Some(vec![String::from("Bridge=br1")]);
+        let actions = plan(&m, &current, latest).unwrap();
+        let actions: Vec<String> = actions.iter().map(|a| a.to_string()).collect();
+        assert_eq!(actions, vec![
+            "stop batch-1",
+            "upgrade postgres-1 from postgres 20260901 to 20261002",
+            "start web-1",
+        ]);
+
+        let actions = plan(&m, &current[1..], latest).unwrap();
+        assert_eq!(actions[0], Action::Stop { name: String::from("batch-1") });
+        assert_eq!(actions[1].to_string(), "create postgres-1 from postgres 20261002");
+
+        let mut moved = Manifest::parse(MANIFEST).unwrap();
+        moved.machines.get_mut("web-2").unwrap().seed = Some(String::from("seed-3"));
+        let actions = plan(&moved, &current, latest).unwrap();
+        assert_eq!(actions[3].to_string(), "move web-2 from seed-2 to seed-3");
+
+        current[2].volumes = vec![Volume::parse("/data:web").unwrap()];
+        assert!(plan(&moved, &current, latest).is_err());
+
+        // a stopped machine stays stopped after an upgrade
+        current[3].version = String::from("0");
+        current[3].running = false;
+        let actions = plan(&m, &current, latest).unwrap();
+        let actions: Vec<String> = actions.iter().map(|a| a.to_string()).collect();
+        assert_eq!(&actions[..2], &["upgrade batch-1 from batch 0 to 1", "stop batch-1"]);
+
+        let mut missing = Manifest::parse(MANIFEST).unwrap();
+        missing.machines.get_mut("web-1").unwrap().version = None;
+        assert!(plan(&missing, &current, latest).is_err());
+    }
+
+    #[test]
+    fn test_plan_replace() {
+        let m = Manifest::parse(MANIFEST).unwrap();
+        let latest = |_: &str| Some(String::from("1"));
+        let mut current = vec![
+            current("postgres-1", "postgres", "1", "seed-1", true),
+            current("web-1", "web", "20261001", "seed-2", true),
+            current("web-2", "web", "20261001", "seed-2", true),
+            current("batch-1", "batch", "1", "seed-3", false),
+        ];
+        current[0].volumes = m.machines["postgres-1"].volumes().unwrap();
+        current[0].group = Some(String::from("db"));
+        current[1].network = Some(vec![String::from("Bridge=br1")]);
+        assert_eq!(plan(&m, &current, latest).unwrap(), vec![]);
+
+        let mut changed = Manifest::parse(MANIFEST).unwrap();
+        changed.machines.get_mut("web-1").unwrap().network = Some(vec![String::from("Bridge=br2")]);
+        changed.machines.get_mut("web-2").unwrap().ca = true;
+        changed.machines.get_mut("postgres-1").unwrap().volumes.push(String::from("/var/log:postgres"));
+        changed.machines.get_mut("batch-1").unwrap().anti_affinity = Some(String::from("batch"));
+        let actions = plan(&changed, &current, latest).unwrap();
+        let actions: Vec<String> = actions.iter().map(|a| a.to_string()).collect();
+        assert_eq!(actions, vec![
+            "replace batch-1 for anti-affinity",
+            "stop batch-1",
+            "replace postgres-1 for volumes",
+            "replace web-1 for network",
+            "replace web-2 for ca",
+        ]);
+
+        // the Seed of web-2 lost a required label
+        let mut required = Manifest::parse(MANIFEST).unwrap();
+        required.machines.get_mut("web-2").unwrap().require = vec![String::from("ssd")];
+        current[2].labels = Some(vec![String::from("hdd")]);
+        assert_eq!(plan(&required, &current, latest).unwrap(), vec![Action::Move {
+            name: String::from("web-2"), from: String::from("seed-2"), to: String::from("another Seed"),
+        }]);
+        current[2].labels = Some(vec![String::from("ssd")]);
+        assert_eq!(plan(&required, &current, latest).unwrap(), vec![]);
+
+        // web-1 and web-2 are in the same group on the same Seed, web-2 moves
+        let mut apart = Manifest::parse(MANIFEST).unwrap();
+        for name in &["web-1", "web-2"] {
+            apart.machines.get_mut(*name).unwrap().anti_affinity = Some(String::from("web"));
+        }
+        current[1].group = Some(String::from("web"));
+        current[2].group = Some(String::from("web"));
+        let actions = plan(&apart, &current, latest).unwrap();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].to_string(), "move web-2 from seed-2 to another Seed");
+        current[2].seed = Some(String::from("seed-3"));
+        assert_eq!(plan(&apart, &current, latest).unwrap(), vec![]);
+    }
+}
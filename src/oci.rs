@@ -0,0 +1,508 @@
+use flate2::read::GzDecoder;
+use regex::Regex;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tar::{Archive, Builder, EntryType, Header};
+
+use crate::{Error, random_pw};
+use crate::image::{METADATA, Metadata, normalize};
+
+/// .wh.name in a layer deletes name from the layers below it
+const WHITEOUT: &str = ".wh.";
+/// hides everything the layers below have in the directory of the marker
+const OPAQUE: &str = ".wh..wh..opq";
+
+const REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+// manifest.json of docker save
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+    config: String,
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Descriptor {
+    #[serde(rename = "mediaType", default)]
+    media_type: String,
+    digest: String,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+    platform: Option<Platform>,
+}
+
+#[derive(Deserialize)]
+struct Platform {
+    architecture: String,
+    os: String,
+}
+
+// index.json and image indexes in blobs
+#[derive(Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct Config {
+    created: Option<String>,
+    config: Option<ContainerConfig>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerConfig {
+    env: Option<Vec<String>>,
+    entrypoint: Option<Vec<String>>,
+    cmd: Option<Vec<String>>,
+    exposed_ports: Option<BTreeMap<String, serde_json::Value>>,
+}
+
+/// Image in an OCI image layout directory or a docker save archive
+pub struct OciImage {
+    dir: PathBuf,
+    // docker save archive unpacked into a temporary directory
+    unpacked: bool,
+    layers: Vec<PathBuf>,
+    pub name: Option<String>,
+    pub tag: Option<String>,
+    pub created: Option<String>,
+    /// Entrypoint followed by Cmd
+    pub entrypoint: Vec<String>,
+    pub env: Vec<String>,
+    pub ports: Vec<u16>,
+}
+
+impl OciImage {
+    /// OCI image layout directory, or .tar archive of one or of docker save,
+    /// as opposed to a rootfs
+    pub fn is_oci(path: &Path) -> bool {
+        let manifest = |p: &str| p == "index.json" || p == "manifest.json";
+        if path.is_dir() {
+            return path.join("index.json").is_file() || path.join("manifest.json").is_file();
+        }
+        if !path.to_str().map(|p| p.ends_with(".tar")).unwrap_or(false) {
+            return false;
+        }
+        let mut archive = match File::open(path) {
+            Ok(file) => Archive::new(file),
+            Err(_)   => return false,
+        };
+        let entries = match archive.entries() {
+            Ok(entries) => entries,
+            Err(_)      => return false,
+        };
+        for entry in entries {
+            match entry.as_ref().map(|e| e.path().map(|p| normalize(&p))) {
+                Ok(Ok(p)) if manifest(&p) => return true,
+                Ok(Ok(_)) => continue,
+                _ => return false,
+            }
+        }
+        false
+    }
+
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        if path.is_dir() {
+            return Self::open_dir(path.to_path_buf(), false);
+        }
+        let dir = std::env::temp_dir().join(format!("barley-oci-{}", random_pw()));
+        fs::create_dir(&dir)?;
+        if let Err(err) = Archive::new(File::open(path)?).unpack(&dir) {
+            fs::remove_dir_all(&dir)?;
+            return Err(Error::from(format!("Failed to unpack {:?}: {}", path, err)));
+        }
+        Self::open_dir(dir, true)
+    }
+
+    fn open_dir(dir: PathBuf, unpacked: bool) -> Result<Self, Error> {
+        let mut image = OciImage {
+            dir,
+            unpacked,
+            layers: Vec::new(),
+            name: None,
+            tag: None,
+            created: None,
+            entrypoint: Vec::new(),
+            env: Vec::new(),
+            ports: Vec::new(),
+        };
+        let config = match image.dir.join("manifest.json").is_file() {
+            true  => image.docker_manifest()?,
+            false => image.oci_manifest()?,
+        };
+        let config: Config = json(&config)?;
+        let c = config.config.unwrap_or_default();
+        image.created = config.created;
+        image.entrypoint = c.entrypoint.unwrap_or_default().into_iter()
+            .chain(c.cmd.unwrap_or_default())
+            .collect();
+        image.env = c.env.unwrap_or_default();
+        // "8080/tcp"
+        image.ports = c.exposed_ports.unwrap_or_default().keys()
+            .filter_map(|p| p.split('/').next()?.parse().ok())
+            .collect();
+        Ok(image)
+    }
+
+    fn docker_manifest(&mut self) -> Result<PathBuf, Error> {
+        let manifests: Vec<DockerManifest> = json(&self.dir.join("manifest.json"))?;
+        let manifest = manifests.into_iter().next().ok_or("No images in manifest.json")?;
+        if let Some(reference) = manifest.repo_tags.unwrap_or_default().first() {
+            self.reference(reference);
+        }
+        self.layers = manifest.layers.iter().map(|l| self.dir.join(l)).collect();
+        Ok(self.dir.join(&manifest.config))
+    }
+
+    fn oci_manifest(&mut self) -> Result<PathBuf, Error> {
+        let index: Index = json(&self.dir.join("index.json"))?;
+        let mut descriptor = linux_amd64(index.manifests).ok_or("No linux/amd64 image in index.json")?;
+        if let Some(reference) = descriptor.annotations.get(REF_NAME).cloned() {
+            self.reference(&reference);
+        }
+        // multi-platform images have an index of per-platform manifests
+        while descriptor.media_type.ends_with("image.index.v1+json") {
+            let index: Index = json(&self.blob(&descriptor.digest)?)?;
+            descriptor = linux_amd64(index.manifests).ok_or("No linux/amd64 image in the image index")?;
+        }
+        let manifest: Manifest = json(&self.blob(&descriptor.digest)?)?;
+        self.layers = manifest.layers.iter()
+            .map(|l| self.blob(&l.digest))
+            .collect::<Result<_, _>>()?;
+        self.blob(&manifest.config.digest)
+    }
+
+    fn blob(&self, digest: &str) -> Result<PathBuf, Error> {
+        let valid = Regex::new(r"^[[:alnum:]]+:[[:xdigit:]]+$").unwrap();
+        if !valid.is_match(digest) {
+            return Err(Error::from(format!("Invalid digest '{}'", digest)));
+        }
+        Ok(self.dir.join("blobs").join(digest.replacen(':', "/", 1)))
+    }
+
+    /// Image name from a repository or file name, with the characters that
+    /// image names don't allow replaced, None when nothing is left
+    pub fn image_name(name: &str) -> Option<String> {
+        let name = Regex::new(r"[^[:alnum:].+-]").unwrap().replace_all(name, "-");
+        Some(name.trim_start_matches(|c: char| !c.is_ascii_alphanumeric()).to_string())
+            .filter(|n| !n.is_empty())
+    }
+
+    // registry.example.com/library/nginx:1.25, or just the tag
+    fn reference(&mut self, reference: &str) {
+        let (repository, tag) = match reference.rfind(':') {
+            Some(i) if !reference[i..].contains('/') => (&reference[..i], Some(&reference[i + 1..])),
+            _ => (reference, None),
+        };
+        let (repository, tag) = match (tag, reference.contains('/')) {
+            (None, false) => ("", Some(repository)),
+            _ => (repository, tag),
+        };
+        self.name = Self::image_name(repository.rsplit('/').next().unwrap_or(""));
+        self.tag = tag.filter(|t| *t != "latest").map(|t| t.to_string());
+    }
+
+    pub fn metadata(&self, name: &str, version: &str) -> Metadata {
+        Metadata {
+            name: name.to_string(),
+            version: Some(version.to_string()),
+            built: self.created.clone(),
+            entrypoint: self.entrypoint.clone(),
+            env: self.env.clone(),
+            ports: self.ports.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn layer(&self, path: &Path) -> Result<Box<dyn Read>, Error> {
+        let mut file = BufReader::new(File::open(path)?);
+        let magic = file.fill_buf()?;
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Ok(Box::new(GzDecoder::new(file)))
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            let zstd = Command::new("zstd").arg("-dcq").arg(path).stdout(Stdio::piped()).spawn()?;
+            Ok(Box::new(zstd.stdout.ok_or("Child process stdout has not been captured.")?))
+        } else {
+            Ok(Box::new(file))
+        }
+    }
+
+    /// Write the layers flattened into a single rootfs tarball, with the image
+    /// metadata in /etc/barley/image.toml
+    pub fn write_rootfs<W: Write>(&self, out: W, metadata: &Metadata) -> Result<W, Error> {
+        // top layer first, a path comes from the highest layer that has it
+        let mut keep = vec![BTreeSet::new(); self.layers.len()];
+        let mut seen: BTreeMap<String, bool> = BTreeMap::new();
+        let mut deleted = BTreeSet::new();
+        let mut opaque = BTreeSet::new();
+        for (i, layer) in self.layers.iter().enumerate().rev() {
+            let mut layer_deleted = Vec::new();
+            let mut layer_opaque = Vec::new();
+            for entry in Archive::new(self.layer(layer)?).entries()? {
+                let entry = entry?;
+                let path = normalize(&entry.path()?);
+                let (dir, file) = match path.rfind('/') {
+                    Some(i) => (&path[..i], &path[i + 1..]),
+                    None    => ("", &path[..]),
+                };
+                if file == OPAQUE {
+                    layer_opaque.push(dir.to_string());
+                } else if let Some(file) = file.strip_prefix(WHITEOUT) {
+                    layer_deleted.push(join(dir, file));
+                } else if !path.is_empty()
+                    && !seen.contains_key(&path)
+                    && !hidden(&path, &seen, &deleted, &opaque) {
+                    seen.insert(path.to_string(), entry.header().entry_type().is_dir());
+                    keep[i].insert(path);
+                }
+            }
+            // whiteouts only apply to the layers below
+            deleted.extend(layer_deleted);
+            opaque.extend(layer_opaque);
+        }
+
+        let mut builder = Builder::new(out);
+        for (i, layer) in self.layers.iter().enumerate() {
+            for entry in Archive::new(self.layer(layer)?).entries()? {
+                let mut entry = entry?;
+                let path = normalize(&entry.path()?);
+                if !keep[i].contains(&path) {
+                    continue;
+                }
+                let mut header = entry.header().clone();
+                match header.entry_type() {
+                    EntryType::Symlink | EntryType::Link => {
+                        let target = entry.link_name()?.ok_or("Link without a target")?;
+                        let target = match header.entry_type() {
+                            EntryType::Link => PathBuf::from(normalize(&target)),
+                            _ => target.to_path_buf(),
+                        };
+                        builder.append_link(&mut header, &path, target)?;
+                    },
+                    _ => builder.append_data(&mut header, &path, &mut entry)?,
+                }
+            }
+        }
+
+        let mut dir = String::new();
+        for component in METADATA.split('/').take_while(|c| !c.ends_with(".toml")) {
+            dir = join(&dir, component);
+            if !seen.contains_key(&dir) {
+                let mut header = Header::new_gnu();
+                header.set_entry_type(EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+                builder.append_data(&mut header, &dir, &[][..])?;
+            }
+        }
+        let toml = metadata.to_toml()?;
+        let mut header = Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_size(toml.len() as u64);
+        builder.append_data(&mut header, METADATA, toml.as_bytes())?;
+        Ok(builder.into_inner()?)
+    }
+}
+
+impl Drop for OciImage {
+    fn drop(&mut self) {
+        if self.unpacked {
+            fs::remove_dir_all(&self.dir).ok();
+        }
+    }
+}
+
+fn json<T: DeserializeOwned>(path: &Path) -> Result<T, Error> {
+    serde_json::from_slice(&fs::read(path)?)
+        .map_err(|err| Error::ConfError(format!("Failed to parse {:?}: {}", path, err)))
+}
+
+fn join(dir: &str, file: &str) -> String {
+    match dir.is_empty() {
+        true  => file.to_string(),
+        false => format!("{}/{}", dir, file),
+    }
+}
+
+// deleted or in an opaque directory of a higher layer, or under a path that a
+// higher layer replaced with something other than a directory
+fn hidden(
+    path: &str,
+    seen: &BTreeMap<String, bool>,
+    deleted: &BTreeSet<String>,
+    opaque: &BTreeSet<String>,
+) -> bool {
+    if deleted.contains(path) {
+        return true;
+    }
+    path.match_indices('/').any(|(i, _)| {
+        let parent = &path[..i];
+        deleted.contains(parent) || opaque.contains(parent) || seen.get(parent) == Some(&false)
+    })
+}
+
+// manifest for linux/amd64, or the only one when the index doesn't say
+fn linux_amd64(manifests: Vec<Descriptor>) -> Option<Descriptor> {
+    if manifests.len() == 1 && manifests[0].platform.is_none() {
+        return manifests.into_iter().next();
+    }
+    manifests.into_iter().find(|m| m.platform.as_ref()
+        .map(|p| p.os == "linux" && p.architecture == "amd64")
+        .unwrap_or(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for (path, data) in files {
+            let mut header = Header::new_gnu();
+            match path.ends_with('/') {
+                true  => header.set_entry_type(EntryType::Directory),
+                false => header.set_entry_type(EntryType::Regular),
+            }
+            header.set_mode(0o644);
+            header.set_size(data.len() as u64);
+            builder.append_data(&mut header, path, data.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_oci_layout() {
+        let dir = std::env::temp_dir().join(format!("barley-test-oci-{}", random_pw()));
+        fs::create_dir_all(dir.join("blobs/sha256")).unwrap();
+        let blob = |hex: &str, data: &[u8]| fs::write(dir.join("blobs/sha256").join(hex), data).unwrap();
+        blob("01", &layer(&[
+            ("./etc/", ""), ("./etc/hostname", "base"), ("./etc/motd", "hello"),
+            ("./var/", ""), ("./var/cache/", ""), ("./var/cache/a", "a"), ("./var/cache/b", "b"),
+            ("./opt", "file"),
+        ]));
+        blob("02", &layer(&[
+            ("etc/.wh.motd", ""), ("var/cache/.wh..wh..opq", ""), ("var/cache/c", "c"),
+            ("etc/hostname", "app"), ("opt/", ""), ("opt/app", "app"),
+        ]));
+        blob("03", br#"{"created": "2026-10-01T12:00:00Z", "config": {
+            "Env": ["PATH=/usr/bin"], "Entrypoint": ["/app"], "Cmd": ["--serve"],
+            "ExposedPorts": {"8080/tcp": {}}}}"#);
+        blob("04", br#"{"schemaVersion": 2,
+            "config": {"mediaType": "application/vnd.oci.image.config.v1+json", "digest": "sha256:03"},
+            "layers": [
+                {"mediaType": "application/vnd.oci.image.layer.v1.tar", "digest": "sha256:01"},
+                {"mediaType": "application/vnd.oci.image.layer.v1.tar", "digest": "sha256:02"}]}"#);
+        fs::write(dir.join("index.json"), br#"{"schemaVersion": 2, "manifests": [
+            {"mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "sha256:04",
+             "annotations": {"org.opencontainers.image.ref.name": "ghcr.io/example/my_app:1.2"}}]}"#)
+            .unwrap();
+
+        assert!(OciImage::is_oci(&dir));
+        let image = OciImage::open(&dir).unwrap();
+        assert_eq!(image.name.as_deref(), Some("my-app"));
+        assert_eq!(image.tag.as_deref(), Some("1.2"));
+        assert_eq!(image.entrypoint, vec!["/app", "--serve"]);
+        assert_eq!(image.ports, vec![8080]);
+
+        let rootfs = image.write_rootfs(Vec::new(), &image.metadata("my-app", "1.2")).unwrap();
+        let mut files = BTreeMap::new();
+        for entry in Archive::new(&rootfs[..]).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut data = String::new();
+            entry.read_to_string(&mut data).unwrap();
+            files.insert(normalize(&entry.path().unwrap()), data);
+        }
+        let paths: Vec<&str> = files.keys().map(|p| p.as_str()).collect();
+        assert_eq!(paths, vec![
+            "etc", "etc/barley", "etc/barley/image.toml", "etc/hostname",
+            "opt", "opt/app", "var", "var/cache", "var/cache/c",
+        ]);
+        assert_eq!(files["etc/hostname"], "app");
+        let metadata = Metadata::parse(&files["etc/barley/image.toml"]).unwrap();
+        assert_eq!(metadata.env, vec!["PATH=/usr/bin"]);
+
+        // one manifest per platform in index.json, arm64 first
+        blob("05", br#"{"schemaVersion": 2,
+            "config": {"mediaType": "application/vnd.oci.image.config.v1+json", "digest": "sha256:06"},
+            "layers": []}"#);
+        blob("06", br#"{"config": {"Entrypoint": ["/arm64"]}}"#);
+        fs::write(dir.join("index.json"), br#"{"schemaVersion": 2, "manifests": [
+            {"mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "sha256:05",
+             "platform": {"architecture": "arm64", "os": "linux"}},
+            {"mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "sha256:04",
+             "platform": {"architecture": "amd64", "os": "linux"}}]}"#)
+            .unwrap();
+        assert_eq!(OciImage::open(&dir).unwrap().entrypoint, vec!["/app", "--serve"]);
+        fs::write(dir.join("index.json"), br#"{"schemaVersion": 2, "manifests": [
+            {"mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "sha256:05",
+             "platform": {"architecture": "arm64", "os": "linux"}}]}"#)
+            .unwrap();
+        assert!(OciImage::open(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_docker_archive() {
+        let mut builder = Builder::new(Vec::new());
+        let mut add = |path: &str, data: &[u8]| {
+            let mut header = Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_size(data.len() as u64);
+            builder.append_data(&mut header, path, data).unwrap();
+        };
+        add("manifest.json", br#"[{"Config": "config.json", "RepoTags": ["nginx:1.25"],
+            "Layers": ["l1/layer.tar"]}]"#);
+        add("config.json", br#"{"config": {"Cmd": ["nginx", "-g", "daemon off;"]}}"#);
+        add("l1/layer.tar", &layer(&[("etc/", ""), ("etc/nginx.conf", "events {}")]));
+        let archive = std::env::temp_dir().join(format!("barley-test-{}.tar", random_pw()));
+        fs::write(&archive, builder.into_inner().unwrap()).unwrap();
+
+        assert!(OciImage::is_oci(&archive));
+        let rootfs = archive.with_extension("rootfs.tar");
+        fs::write(&rootfs, layer(&[("etc/", ""), ("etc/hostname", "web")])).unwrap();
+        assert!(!OciImage::is_oci(&rootfs));
+        fs::remove_file(&rootfs).unwrap();
+        let image = OciImage::open(&archive).unwrap();
+        let dir = image.dir.clone();
+        assert_eq!(image.tag.as_deref(), Some("1.25"));
+        assert_eq!(image.entrypoint, vec!["nginx", "-g", "daemon off;"]);
+        let rootfs = image.write_rootfs(Vec::new(), &image.metadata("nginx", "1.25")).unwrap();
+        assert_eq!(Archive::new(&rootfs[..]).entries().unwrap().count(), 4);
+        drop(image);
+        assert!(!dir.exists());
+        fs::remove_file(&archive).unwrap();
+    }
+
+    #[test]
+    fn test_reference() {
+        let image = |r: &str| {
+            let mut i = OciImage {
+                dir: PathBuf::new(), unpacked: false, layers: Vec::new(), name: None, tag: None,
+                created: None, entrypoint: Vec::new(), env: Vec::new(), ports: Vec::new(),
+            };
+            i.reference(r);
+            (i.name.clone(), i.tag.clone())
+        };
+        assert_eq!(image("nginx:1.25"), (Some(String::from("nginx")), Some(String::from("1.25"))));
+        assert_eq!(image("localhost:5000/web"), (Some(String::from("web")), None));
+        assert_eq!(image("docker.io/library/postgres:latest"), (Some(String::from("postgres")), None));
+        assert_eq!(image("3.19"), (None, Some(String::from("3.19"))));
+        assert_eq!(OciImage::image_name("my_app image").as_deref(), Some("my-app-image"));
+        assert_eq!(OciImage::image_name("_web").as_deref(), Some("web"));
+        assert_eq!(OciImage::image_name("__"), None);
+    }
+}
@@ -0,0 +1,192 @@
+use std::cmp::Ordering;
+
+use crate::{Error, SeedInfo, Status};
+
+// Seeds report their status every minute
+pub const STALE_SECS: u64 = 180;
+
+pub trait Policy {
+    /// Seeds that compare as Less are picked first
+    fn cmp(&self, a: &Status, b: &Status) -> Ordering;
+}
+
+/// Lowest CPU load per core, then most free memory
+pub struct LeastLoaded;
+
+impl Policy for LeastLoaded {
+    fn cmp(&self, a: &Status, b: &Status) -> Ordering {
+        a.load_per_cpu().partial_cmp(&b.load_per_cpu()).unwrap_or(Ordering::Equal)
+            .then(b.mem_free.cmp(&a.mem_free))
+    }
+}
+
+/// Fewest machines, then most free memory
+pub struct Spread;
+
+impl Policy for Spread {
+    fn cmp(&self, a: &Status, b: &Status) -> Ordering {
+        a.machines.cmp(&b.machines).then(b.mem_free.cmp(&a.mem_free))
+    }
+}
+
+/// Least free memory that still fits the machine, keeps other Seeds empty
+pub struct BinPack;
+
+impl Policy for BinPack {
+    fn cmp(&self, a: &Status, b: &Status) -> Ordering {
+        a.mem_free.cmp(&b.mem_free).then(b.machines.cmp(&a.machines))
+    }
+}
+
+pub fn policy(name: &str) -> Result<Box<dyn Policy>, Error> {
+    match name {
+        "least-loaded" => Ok(Box::new(LeastLoaded)),
+        "spread"       => Ok(Box::new(Spread)),
+        "bin-pack"     => Ok(Box::new(BinPack)),
+        _ => Err(Error::from(format!(
+            "Unknown scheduling policy '{}', use least-loaded, spread, or bin-pack", name))),
+    }
+}
+
+#[derive(Default)]
+pub struct Requirements {
+    pub memory: u64,
+    /// Free space in the volume group for the volumes of the machine
+    pub disk: u64,
+    /// Seed must have all of these labels
+    pub labels: Vec<String>,
+    /// Seeds with more of these labels are picked first
+    pub prefer: Vec<String>,
+    /// Seeds that already run a machine from the same anti-affinity group
+    pub avoid: Vec<String>,
+}
+
+impl Requirements {
+    fn allows(&self, seed: &SeedInfo) -> bool {
+        let status = seed.status.as_ref().unwrap();
+        status.mem_free >= self.memory
+            && status.disk_free >= self.disk
+            && self.labels.iter().all(|l| seed.labels.contains(l))
+            && !self.avoid.contains(&seed.name)
+    }
+
+    /// Check a Seed that was picked by hand against the labels and
+    /// anti-affinity, memory and disk are up to whoever picked it
+    pub fn check(&self, seed: &SeedInfo) -> Result<(), Error> {
+        if let Some(l) = self.labels.iter().find(|l| !seed.labels.contains(l)) {
+            return Err(Error::from(format!("Seed {} doesn't have the required label {}", &seed.name, l)));
+        }
+        if self.avoid.contains(&seed.name) {
+            return Err(Error::from(format!(
+                "Seed {} already runs a machine from the same anti-affinity group", &seed.name)));
+        }
+        Ok(())
+    }
+
+    fn preference(&self, seed: &SeedInfo) -> usize {
+        self.prefer.iter().filter(|l| seed.labels.contains(l)).count()
+    }
+}
+
+pub fn healthy(seed: &SeedInfo, now: u64) -> bool {
+    match &seed.status {
+        Some(s) => s.updated + STALE_SECS >= now,
+        None    => false,
+    }
+}
+
+pub fn pick<'a>(
+    policy: &dyn Policy,
+    req: &Requirements,
+    seeds: &'a [SeedInfo],
+    now: u64,
+) -> Option<&'a SeedInfo> {
+    seeds.iter()
+        .filter(|s| healthy(s, now))
+        .filter(|s| req.allows(s))
+        .min_by(|a, b| req.preference(b).cmp(&req.preference(a))
+            .then(policy.cmp(a.status.as_ref().unwrap(), b.status.as_ref().unwrap())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seed(name: &str, mem_free: u64, load: f32, machines: u32, updated: u64) -> SeedInfo {
+        let disk_free = match name {
+            "seed-2" => 100 << 30,
+            _        => 0,
+        };
+        SeedInfo {
+            name: name.to_string(),
+            ip: String::from("127.0.0.1"),
+            labels: Vec::new(),
+            status: Some(Status {
+                mem_total: 8 << 30,
+                mem_free,
+                cpus: 4,
+                load,
+                disk_free,
+                machines,
+                running: Vec::new(),
+                updated,
+            }),
+        }
+    }
+
+    fn seeds() -> Vec<SeedInfo> {
+        vec![
+            seed("seed-1", 6 << 30, 2.0, 1, 1000),
+            seed("seed-2", 2 << 30, 0.5, 3, 1000),
+            seed("seed-3", 4 << 30, 0.1, 0, 1000),
+            seed("seed-4", 7 << 30, 0.0, 0, 100),
+        ]
+    }
+
+    #[test]
+    fn test_policies() {
+        let seeds = seeds();
+        let req = Requirements { memory: 512 << 20, ..Default::default() };
+        let name = |p: &str| pick(&*policy(p).unwrap(), &req, &seeds, 1000).unwrap().name.to_string();
+        assert_eq!(name("least-loaded"), "seed-3");
+        assert_eq!(name("spread"), "seed-3");
+        assert_eq!(name("bin-pack"), "seed-2");
+        assert!(policy("random").is_err());
+    }
+
+    #[test]
+    fn test_requirements() {
+        let seeds = seeds();
+        let req = Requirements { memory: 5 << 30, ..Default::default() };
+        assert_eq!(pick(&BinPack, &req, &seeds, 1000).unwrap().name, "seed-1");
+        let req = Requirements { memory: 7 << 30, ..Default::default() };
+        assert!(pick(&BinPack, &req, &seeds, 1000).is_none());
+        let req = Requirements { disk: 32 << 30, ..Default::default() };
+        assert_eq!(pick(&Spread, &req, &seeds, 1000).unwrap().name, "seed-2");
+    }
+
+    #[test]
+    fn test_placement() {
+        let mut seeds = seeds();
+        seeds[0].labels = vec![String::from("dmz"), String::from("ssd")];
+        seeds[1].labels = vec![String::from("dmz")];
+        let labels = |l: &[&str]| l.iter().map(|s| s.to_string()).collect();
+
+        let req = Requirements { labels: labels(&["dmz"]), ..Default::default() };
+        assert_eq!(pick(&Spread, &req, &seeds, 1000).unwrap().name, "seed-1");
+
+        let req = Requirements { labels: labels(&["dmz"]), avoid: labels(&["seed-1"]), ..Default::default() };
+        assert_eq!(pick(&Spread, &req, &seeds, 1000).unwrap().name, "seed-2");
+
+        let req = Requirements { prefer: labels(&["ssd"]), ..Default::default() };
+        assert_eq!(pick(&Spread, &req, &seeds, 1000).unwrap().name, "seed-1");
+
+        let req = Requirements { labels: labels(&["gpu"]), ..Default::default() };
+        assert!(pick(&Spread, &req, &seeds, 1000).is_none());
+
+        let req = Requirements { labels: labels(&["dmz"]), avoid: labels(&["seed-2"]), ..Default::default() };
+        assert!(req.check(&seeds[0]).is_ok());
+        assert!(req.check(&seeds[1]).is_err());
+        assert!(req.check(&seeds[2]).is_err());
+    }
+}
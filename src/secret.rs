@@ -0,0 +1,173 @@
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+use crate::Error;
+
+const KEYRING_SERVICE: &str = "barley";
+const ENVELOPE_NAMESPACE: &str = "barley-envelope";
+
+pub fn from_fd(fd: i32) -> Result<String, Error> {
+    // the descriptor is handed to us by the caller and is not used anywhere else
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    let mut pw = String::new();
+    file.read_to_string(&mut pw)?;
+    Ok(pw.trim_end_matches('\n').to_string())
+}
+
+pub fn keyring_lookup(id: &str) -> Option<String> {
+    let output = Command::new("/usr/bin/secret-tool")
+        .arg("lookup")
+        .arg("service").arg(KEYRING_SERVICE)
+        .arg("field").arg(id)
+        .stderr(Stdio::null())
+        .output().ok()?;
+    if output.status.success() && !output.stdout.is_empty() {
+        String::from_utf8(output.stdout).ok()
+    } else {
+        None
+    }
+}
+
+pub fn keyring_store(id: &str, pw: &str) -> Result<(), Error> {
+    let mut child = Command::new("/usr/bin/secret-tool")
+        .arg("store")
+        .arg("--label").arg(format!("Barley field {} root.key", &id))
+        .arg("service").arg(KEYRING_SERVICE)
+        .arg("field").arg(id)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().ok_or("Child process stdin has not been captured.")?
+        .write_all(pw.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(Error::CommandError(format!("secret-tool store failed: {:?}", status)));
+    }
+    Ok(())
+}
+
+// Ed25519 signatures are deterministic, so the same agent key signing the
+// same message always yields the same age identity.
+fn identity(id: &str, key: &PathBuf) -> Result<String, Error> {
+    match fs::read_to_string(key) {
+        Ok(k) if k.starts_with("ssh-ed25519 ") => {},
+        Ok(_)    => return Err(Error::from(format!("{:?} is not an ed25519 key", key))),
+        Err(err) => return Err(Error::IoError(format!("Failed to read {:?}: {}", key, err))),
+    }
+    let output = pipe(Command::new("/usr/bin/ssh-keygen")
+        .arg("-q")
+        .arg("-Y").arg("sign")
+        .arg("-n").arg(ENVELOPE_NAMESPACE)
+        .arg("-f").arg(key), id)?;
+    if !output.status.success() {
+        return Err(Error::CommandError(format!(
+            "Failed to sign with {:?}, is it loaded into ssh-agent?", key)));
+    }
+    let secret = Sha256::digest(&output.stdout);
+    Ok(format!("{}\n", bech32("age-secret-key-", &secret).to_uppercase()))
+}
+
+// run a command with input on its stdin, so that secrets never touch the disk
+fn pipe(command: &mut Command, input: &str) -> Result<Output, Error> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().ok_or("Child process stdin has not been captured.")?
+        .write_all(input.as_bytes())?;
+    Ok(child.wait_with_output()?)
+}
+
+pub fn envelope_seal(id: &str, key: &PathBuf, envelope: &PathBuf, pw: &str) -> Result<(), Error> {
+    let identity = identity(id, key)?;
+    let recipient = pipe(Command::new("/usr/bin/age-keygen").arg("-y"), &identity)?;
+    if !recipient.status.success() {
+        return Err(Error::CommandError(format!("age-keygen failed: {:?}", recipient.status)));
+    }
+    let mut child = Command::new("/usr/bin/age")
+        .arg("-e")
+        .arg("-r").arg(String::from_utf8(recipient.stdout)?.trim())
+        .arg("-o").arg(envelope)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().ok_or("Child process stdin has not been captured.")?
+        .write_all(pw.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(Error::CommandError(format!("age failed: {:?}", status)));
+    }
+    Ok(())
+}
+
+pub fn envelope_open(id: &str, key: &PathBuf, envelope: &PathBuf) -> Result<String, Error> {
+    let identity = identity(id, key)?;
+    let output = pipe(Command::new("/usr/bin/age")
+        .arg("-d")
+        .arg("-i").arg("-")
+        .arg(envelope), &identity)?;
+    if !output.status.success() {
+        return Err(Error::CommandError(format!("Failed to open {:?}", envelope)));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+const CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn polymod(values: &[u8]) -> u32 {
+    let generator = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut chk: u32 = 1;
+    for v in values {
+        let b = chk >> 25;
+        chk = (chk & 0x1ffffff) << 5 ^ *v as u32;
+        for (i, g) in generator.iter().enumerate() {
+            if (b >> i) & 1 == 1 {
+                chk ^= g;
+            }
+        }
+    }
+    chk
+}
+
+pub fn bech32(hrp: &str, data: &[u8]) -> String {
+    let mut words = Vec::new();
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for byte in data {
+        acc = acc << 8 | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            words.push((acc >> bits & 31) as u8);
+        }
+    }
+    if bits > 0 {
+        words.push((acc << (5 - bits) & 31) as u8);
+    }
+    let mut values: Vec<u8> = hrp.bytes().map(|c| c >> 5).collect();
+    values.push(0);
+    values.extend(hrp.bytes().map(|c| c & 31));
+    values.extend(&words);
+    values.extend(&[0; 6]);
+    let checksum = polymod(&values) ^ 1;
+    words.extend((0..6).map(|i| (checksum >> (5 * (5 - i)) & 31) as u8));
+    let encoded: String = words.iter().map(|w| CHARSET[*w as usize] as char).collect();
+    format!("{}1{}", hrp, encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bech32() {
+        assert_eq!(bech32("a", &[]), "a12uel5l");
+        let data = [
+            0x00, 0x44, 0x32, 0x14, 0xc7, 0x42, 0x54, 0xb6, 0x35, 0xcf,
+            0x84, 0x65, 0x3a, 0x56, 0xd7, 0xc6, 0x75, 0xbe, 0x77, 0xdf,
+        ];
+        assert_eq!(bech32("abcdef", &data), "abcdef1qpzry9x8gf2tvdw0s3jn54khce6mua7lmqqqxw");
+    }
+}
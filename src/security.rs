@@ -0,0 +1,144 @@
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::Error;
+use crate::dpkg::{self, Package};
+
+// https://security-tracker.debian.org/tracker/data/json, issues by source package
+#[derive(Deserialize)]
+struct Issue {
+    #[serde(default)]
+    releases: BTreeMap<String, Release>,
+}
+
+#[derive(Deserialize)]
+struct Release {
+    status: String,
+    fixed_version: Option<String>,
+    urgency: Option<String>,
+}
+
+/// Snapshot of the Debian security tracker
+pub struct Tracker {
+    packages: BTreeMap<String, BTreeMap<String, Issue>>,
+}
+
+/// Open issue affecting an installed package
+#[derive(Debug, PartialEq)]
+pub struct Vulnerability {
+    pub id: String,
+    pub package: String,
+    pub source: String,
+    pub installed: String,
+    /// None until the release has a fix
+    pub fixed: Option<String>,
+    pub urgency: String,
+}
+
+impl Tracker {
+    pub fn parse(json: &[u8]) -> Result<Self, Error> {
+        let packages = serde_json::from_slice(json)
+            .map_err(|err| Error::ConfError(format!("Invalid security tracker data: {}", err)))?;
+        Ok(Tracker { packages })
+    }
+
+    pub fn load(path: &PathBuf) -> Result<Self, Error> {
+        let json = fs::read(path)
+            .map_err(|err| Error::ConfError(format!("Failed to read {:?}: {}", path, err)))?;
+        Self::parse(&json)
+    }
+
+    /// Issues of a release that are unfixed or fixed in a later version than
+    /// the installed one, unimportant ones only with all
+    pub fn check(&self, release: &str, packages: &[Package], all: bool) -> Vec<Vulnerability> {
+        let mut vulnerabilities = Vec::new();
+        for package in packages {
+            let issues = match self.packages.get(&package.source) {
+                Some(issues) => issues,
+                None => continue,
+            };
+            for (id, issue) in issues {
+                let r = match issue.releases.get(release) {
+                    Some(r) => r,
+                    None => continue,
+                };
+                let urgency = r.urgency.as_deref().unwrap_or("not yet assigned");
+                if !all && (urgency == "unimportant" || urgency == "end-of-life") {
+                    continue;
+                }
+                let fixed = match r.status.as_str() {
+                    "open" | "undetermined" => None,
+                    "resolved" => match r.fixed_version.as_deref() {
+                        // "0" marks releases that never had the issue
+                        Some(v) if v != "0" && dpkg::compare_versions(
+                            &package.source_version, v) == Ordering::Less => Some(v.to_string()),
+                        _ => continue,
+                    },
+                    _ => continue,
+                };
+                vulnerabilities.push(Vulnerability {
+                    id: id.to_string(),
+                    package: package.name.to_string(),
+                    source: package.source.to_string(),
+                    installed: package.version.to_string(),
+                    fixed,
+                    urgency: urgency.to_string(),
+                });
+            }
+        }
+        vulnerabilities
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACKER: &str = r#"{
+        "openssl": {
+            "CVE-2024-0001": {"description": "fixed in a point release", "scope": "remote",
+                "releases": {
+                    "bookworm": {"status": "resolved", "fixed_version": "3.0.14-1~deb12u1",
+                        "urgency": "high", "repositories": {"bookworm": "3.0.14-1~deb12u1"}},
+                    "trixie": {"status": "resolved", "fixed_version": "3.2.0-1", "urgency": "high"}}},
+            "CVE-2024-0002": {"releases": {
+                    "bookworm": {"status": "resolved", "fixed_version": "3.0.9-1", "urgency": "medium"}}},
+            "CVE-2024-0003": {"releases": {
+                    "bookworm": {"status": "open", "urgency": "unimportant"}}},
+            "CVE-2024-0004": {"releases": {
+                    "bookworm": {"status": "resolved", "fixed_version": "0", "urgency": "low"}}}
+        },
+        "vim": {
+            "CVE-2024-0005": {"releases": {
+                    "bookworm": {"status": "open", "urgency": "low"}}}
+        }
+    }"#;
+
+    #[test]
+    fn test_check() {
+        let tracker = Tracker::parse(TRACKER.as_bytes()).unwrap();
+        let packages = dpkg::parse_status("\
+Package: libssl3
+Status: install ok installed
+Source: openssl (3.0.11-1~deb12u2)
+Version: 3.0.11-1~deb12u2
+
+Package: vim-tiny
+Status: install ok installed
+Source: vim
+Version: 2:9.0.1378-2
+");
+        let found = tracker.check("bookworm", &packages, false);
+        let ids: Vec<&str> = found.iter().map(|v| v.id.as_str()).collect();
+        assert_eq!(ids, vec!["CVE-2024-0001", "CVE-2024-0005"]);
+        assert_eq!(found[0].package, "libssl3");
+        assert_eq!(found[0].fixed.as_deref(), Some("3.0.14-1~deb12u1"));
+        assert_eq!(found[1].fixed, None);
+        assert_eq!(tracker.check("bookworm", &packages, true).len(), 3);
+        assert!(tracker.check("bullseye", &packages, true).is_empty());
+        assert!(Tracker::parse(b"[]").is_err());
+    }
+}
@@ -1,15 +1,17 @@
-use std::fs;
+use std::fs::{self, File};
 use std::path::PathBuf;
-use std::process::Command;
+use std::process::{Command, Stdio};
 
 use crate::Error;
 
+pub const IMAGE_NAMESPACE: &str = "barley-image";
+
 pub fn sign(id: &str, ca: &PathBuf, key: &PathBuf) -> Result<(), Error> {
     let status = Command::new("/usr/bin/ssh-keygen")
-        .arg("-I").arg(&id)
-        .arg("-s").arg(&ca)
+        .arg("-I").arg(id)
+        .arg("-s").arg(ca)
         .arg("-h")
-        .arg(&key)
+        .arg(key)
         .status()?;
     match status.success() {
         true  => Ok(()),
@@ -17,9 +19,245 @@ pub fn sign(id: &str, ca: &PathBuf, key: &PathBuf) -> Result<(), Error> {
     }
 }
 
-pub fn authorized_keys(path: &PathBuf) -> Result<String, Error> {
-    match fs::read_to_string(&path) {
-        Ok(key)  => Ok(format!("{}\ncert-authority {}", key, key)),
+// authorized_keys(5) line with optional options in front of the key
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdminKey {
+    pub options: Vec<String>,
+    pub key: String,
+}
+
+impl AdminKey {
+    pub fn new(key: &str, principals: Option<String>, expiry: Option<String>) -> Self {
+        let mut options = Vec::new();
+        if let Some(p) = principals {
+            options.push(format!("principals=\"{}\"", p));
+        }
+        if let Some(e) = expiry {
+            options.push(format!("expiry-time=\"{}\"", e));
+        }
+        AdminKey { options, key: key.trim().to_string() }
+    }
+
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        if is_key_type(line) {
+            return Some(AdminKey { options: Vec::new(), key: line.to_string() });
+        }
+        let mut quoted = false;
+        let mut options = Vec::new();
+        let mut option = String::new();
+        for (i, c) in line.char_indices() {
+            match c {
+                '"' => { quoted = !quoted; option.push(c); },
+                ',' if !quoted => { options.push(option); option = String::new(); },
+                ' ' | '\t' if !quoted => {
+                    options.push(option);
+                    let key = line[i..].trim();
+                    return match is_key_type(key) {
+                        true  => Some(AdminKey { options, key: key.to_string() }),
+                        false => None,
+                    };
+                },
+                _ => option.push(c),
+            }
+        }
+        None
+    }
+
+    pub fn comment(&self) -> &str {
+        self.key.splitn(3, ' ').nth(2).unwrap_or("")
+    }
+
+    pub fn option(&self, name: &str) -> &str {
+        self.options.iter()
+            .filter_map(|o| o.strip_prefix(name)?.strip_prefix('='))
+            .map(|v| v.trim_matches('"'))
+            .next().unwrap_or("")
+    }
+
+    pub fn to_line(&self) -> String {
+        match self.options.is_empty() {
+            true  => self.key.to_string(),
+            false => format!("{} {}", self.options.join(","), self.key),
+        }
+    }
+
+    // principals= is only allowed together with cert-authority, and a plain
+    // login with the key would get around it, so such keys only sign
+    fn authorized_keys(&self) -> String {
+        let mut ca = vec!["cert-authority"];
+        ca.extend(self.options.iter().map(|o| o.as_str()));
+        let ca = format!("{} {}\n", ca.join(","), self.key);
+        match (self.options.iter().any(|o| o.starts_with("principals=")), self.options.is_empty()) {
+            (true, _)      => ca,
+            (false, true)  => format!("{}\n{}", self.key, ca),
+            (false, false) => format!("{} {}\n{}", self.options.join(","), self.key, ca),
+        }
+    }
+}
+
+fn is_key_type(s: &str) -> bool {
+    s.starts_with("ssh-") || s.starts_with("ecdsa-") || s.starts_with("sk-")
+}
+
+pub fn admin_keys(path: &PathBuf) -> Result<Vec<AdminKey>, Error> {
+    match fs::read_to_string(path) {
+        Ok(keys) => Ok(keys.lines().filter_map(AdminKey::parse).collect()),
         Err(err) => Err(Error::IoError(format!("Failed to read {:?}: {}", path, err))),
     }
 }
+
+pub fn write_admin_keys(path: &PathBuf, keys: &[AdminKey]) -> Result<(), Error> {
+    let keys: String = keys.iter().map(|k| format!("{}\n", k.to_line())).collect();
+    fs::write(path, keys)
+        .map_err(|err| Error::IoError(format!("Failed to write {:?}: {}", path, err)))
+}
+
+pub fn sign_user(
+    id: &str,
+    ca: &PathBuf,
+    principals: &str,
+    validity: &str,
+    serial: u64,
+    options: &[String],
+    key: &PathBuf,
+) -> Result<PathBuf, Error> {
+    let mut keygen = Command::new("/usr/bin/ssh-keygen");
+    keygen
+        .arg("-q")
+        .arg("-I").arg(id)
+        .arg("-s").arg(ca)
+        .arg("-n").arg(principals)
+        .arg("-V").arg(validity)
+        .arg("-z").arg(serial.to_string());
+    if ca.extension() == Some("pub".as_ref()) {
+        // only the public half is available, private key is in ssh-agent
+        keygen.arg("-U");
+    }
+    for option in options {
+        keygen.arg("-O").arg(option);
+    }
+    let status = keygen.arg(key).status()?;
+    if !status.success() {
+        return Err(Error::CertError());
+    }
+    let stem = key.to_str().ok_or("Invalid key path")?;
+    Ok(PathBuf::from(format!("{}-cert.pub", stem.strip_suffix(".pub").unwrap_or(stem))))
+}
+
+pub fn revoke(krl: &PathBuf, certs: &[PathBuf]) -> Result<(), Error> {
+    let status = Command::new("/usr/bin/ssh-keygen")
+        .arg("-q")
+        .arg("-k")
+        .arg("-f").arg(krl)
+        .args(certs)
+        .status()?;
+    match status.success() {
+        true  => Ok(()),
+        false => Err(Error::CertError()),
+    }
+}
+
+pub fn authorized_keys(path: &PathBuf) -> Result<String, Error> {
+    Ok(admin_keys(path)?.iter().map(|k| k.authorized_keys()).collect())
+}
+
+// allowed_signers(5) line that only trusts the key to sign images
+pub fn build_key(principal: &str, key: &str) -> String {
+    let key: Vec<&str> = key.split_whitespace().take(2).collect();
+    format!("{} namespaces=\"{}\" {}", principal, IMAGE_NAMESPACE, key.join(" "))
+}
+
+// principals of build keys in an allowed_signers file
+pub fn build_keys(path: &PathBuf) -> Vec<(String, String)> {
+    fs::read_to_string(path).unwrap_or_default().lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut f = line.splitn(2, ' ');
+            Some((f.next()?.to_string(), f.next()?.to_string()))
+        })
+        .collect()
+}
+
+// returns the principal of the build key that made the signature
+pub fn verify(signers: &PathBuf, signature: &PathBuf, file: &PathBuf) -> Result<String, Error> {
+    let output = Command::new("/usr/bin/ssh-keygen")
+        .arg("-Y").arg("find-principals")
+        .arg("-f").arg(signers)
+        .arg("-s").arg(signature)
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::CommandError(format!("{:?} is not signed by a trusted build key", file)));
+    }
+    let principal = String::from_utf8(output.stdout)?.lines().next().unwrap_or("").to_string();
+    let status = Command::new("/usr/bin/ssh-keygen")
+        .arg("-q")
+        .arg("-Y").arg("verify")
+        .arg("-f").arg(signers)
+        .arg("-I").arg(&principal)
+        .arg("-n").arg(IMAGE_NAMESPACE)
+        .arg("-s").arg(signature)
+        .stdin(Stdio::from(File::open(file)?))
+        .stdout(Stdio::null())
+        .status()?;
+    match status.success() {
+        true  => Ok(principal),
+        false => Err(Error::CommandError(format!("Bad signature {:?} for {:?}", signature, file))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_key() {
+        let key = AdminKey::parse("ssh-ed25519 AAAA alice@example").unwrap();
+        assert!(key.options.is_empty());
+        assert_eq!(key.comment(), "alice@example");
+        assert_eq!(key.authorized_keys(),
+            "ssh-ed25519 AAAA alice@example\ncert-authority ssh-ed25519 AAAA alice@example\n");
+    }
+
+    #[test]
+    fn test_admin_key_options() {
+        let line = r#"principals="root,barley",expiry-time="20261231" ssh-ed25519 AAAA bob"#;
+        let key = AdminKey::parse(line).unwrap();
+        assert_eq!(key.option("principals"), "root,barley");
+        assert_eq!(key.option("expiry-time"), "20261231");
+        assert_eq!(key.to_line(), line);
+        // restricted to principals, so no plain root login
+        assert_eq!(key.authorized_keys(), format!("cert-authority,{}\n", line));
+        let carol = AdminKey::parse(r#"expiry-time="20261231" ssh-ed25519 AAAA carol"#).unwrap();
+        assert_eq!(carol.authorized_keys(),
+            "expiry-time=\"20261231\" ssh-ed25519 AAAA carol\n\
+             cert-authority,expiry-time=\"20261231\" ssh-ed25519 AAAA carol\n");
+        assert_eq!(key, AdminKey::new(
+            "ssh-ed25519 AAAA bob\n",
+            Some("root,barley".to_string()),
+            Some("20261231".to_string()),
+        ));
+    }
+
+    #[test]
+    fn test_build_key() {
+        let line = build_key("ci", "ssh-ed25519 AAAA ci@example\n");
+        assert_eq!(line, r#"ci namespaces="barley-image" ssh-ed25519 AAAA"#);
+        let path = PathBuf::from(format!("/tmp/barley-build-keys-{}", crate::random_pw()));
+        fs::write(&path, format!("# build keys\n{}\n\n", line)).unwrap();
+        let keys = build_keys(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(keys, vec![(String::from("ci"), String::from(r#"namespaces="barley-image" ssh-ed25519 AAAA"#))]);
+    }
+
+    #[test]
+    fn test_admin_key_invalid() {
+        assert_eq!(AdminKey::parse(""), None);
+        assert_eq!(AdminKey::parse("# comment"), None);
+        assert_eq!(AdminKey::parse("command=\"true\" not-a-key"), None);
+    }
+}
@@ -1,11 +1,12 @@
+use chrono::NaiveDateTime;
 use rand::random;
 use std::fs;
-use std::path::PathBuf;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 
 use crate::{Error, random_pw};
 
-fn conf_path(cert: &PathBuf) -> PathBuf {
+fn conf_path(cert: &Path) -> PathBuf {
     cert.parent().unwrap_or(
         &PathBuf::from("/tmp")
     ).join(format!("ca-{}.conf", random::<u128>()))
@@ -18,12 +19,12 @@ pub fn generate_root(id: &str, key: &PathBuf, cert: &PathBuf) -> Result<String,
         .arg("--key-type").arg("ed25519")
         .arg("--pkcs-cipher").arg("aes-256")
         .arg("--password").arg(&pw)
-        .arg("--outfile").arg(&key)
+        .arg("--outfile").arg(key)
         .status()?;
     if !status.success() {
         return Err(Error::CertError());
     }
-    let conf = conf_path(&cert);
+    let conf = conf_path(cert);
     fs::write(&conf, format!(r"dn=cn={}
 expiration_days=1825
 ca
@@ -33,8 +34,8 @@ crl_signing_key", &id))?;
         .env("GNUTLS_PIN", &pw)
         .arg("--generate-self-signed")
         .arg("--template").arg(&conf)
-        .arg("--load-privkey").arg(&key)
-        .arg("--outfile").arg(&cert)
+        .arg("--load-privkey").arg(key)
+        .arg("--outfile").arg(cert)
         .status()?;
     fs::remove_file(conf)?;
     if !status.success() {
@@ -43,27 +44,122 @@ crl_signing_key", &id))?;
     Ok(pw)
 }
 
+pub fn change_password(key: &PathBuf, old: Option<&str>, new: &str) -> Result<(), Error> {
+    let mut certtool = Command::new("/usr/bin/certtool");
+    if let Some(pw) = old {
+        certtool.env("GNUTLS_PIN", pw);
+    }
+    let mut decrypt = certtool
+        .arg("--key-info")
+        .arg("--no-text")
+        .arg("--load-privkey").arg(key)
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let outfile = key.with_extension("new");
+    let status = Command::new("/usr/bin/certtool")
+        .arg("--to-p8")
+        .arg("--pkcs-cipher").arg("aes-256")
+        .arg("--password").arg(new)
+        .arg("--load-privkey").arg("/dev/stdin")
+        .arg("--outfile").arg(&outfile)
+        .stdin(decrypt.stdout.take().ok_or("Child process stdout has not been captured.")?)
+        .status()?;
+    if !decrypt.wait()?.success() || !status.success() {
+        fs::remove_file(&outfile).ok();
+        return Err(Error::CertError());
+    }
+    fs::rename(&outfile, key)?;
+    Ok(())
+}
+
 pub fn generate(key: &PathBuf) -> Result<(), Error> {
     let status = Command::new("/usr/bin/certtool")
         .arg("--generate-privkey")
         .arg("--key-type").arg("ed25519")
         .arg("--no-text")
-        .arg("--outfile").arg(&key)
+        .arg("--outfile").arg(key)
+        .status()?;
+    if !status.success() {
+        return Err(Error::CertError());
+    }
+    Ok(())
+}
+
+pub fn cross_sign(
+    id: &str,
+    cacert: &PathBuf,
+    cakey: &PathBuf,
+    pw: Option<&str>,
+    root: &PathBuf,
+    cert: &PathBuf,
+) -> Result<(), Error> {
+    // the old root can't vouch for the new one any longer than it is valid itself
+    let expiration = expiration(cacert)?;
+    let pubkey = conf_path(cert).with_extension("pub");
+    let status = Command::new("/usr/bin/certtool")
+        .arg("--pubkey-info")
+        .arg("--no-text")
+        .arg("--load-cert").arg(root)
+        .arg("--outfile").arg(&pubkey)
         .status()?;
     if !status.success() {
         return Err(Error::CertError());
     }
+    let conf = conf_path(cert);
+    fs::write(&conf, format!(r#"dn=cn={}
+expiration_date="{}"
+ca
+cert_signing_key
+crl_signing_key"#, &id, &expiration))?;
+    let mut certtool = Command::new("/usr/bin/certtool");
+    match pw {
+        Some(pw) => certtool.env("GNUTLS_PIN", pw),
+        None     => certtool.arg("--ask-pass"),
+    };
+    let status = certtool
+        .arg("--generate-certificate")
+        .arg("--template").arg(&conf)
+        .arg("--load-pubkey").arg(&pubkey)
+        .arg("--load-ca-certificate").arg(cacert)
+        .arg("--load-ca-privkey").arg(cakey)
+        .arg("--outfile").arg(cert)
+        .status()?;
+    fs::remove_file(conf)?;
+    fs::remove_file(pubkey)?;
+    if !status.success() {
+        return Err(Error::CertError());
+    }
     Ok(())
 }
 
+// Not After of a certificate in the format of certtool templates
+fn expiration(cert: &PathBuf) -> Result<String, Error> {
+    let output = Command::new("/usr/bin/certtool")
+        .arg("--certificate-info")
+        .arg("--infile").arg(cert)
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::CertError());
+    }
+    parse_not_after(&String::from_utf8(output.stdout)?)
+        .ok_or_else(|| Error::from(format!("Expiration date of {:?} not found", cert)))
+}
+
+fn parse_not_after(info: &str) -> Option<String> {
+    let date = info.lines().find_map(|l| l.trim().strip_prefix("Not After: "))?;
+    let date = NaiveDateTime::parse_from_str(date.trim(), "%a %b %d %H:%M:%S UTC %Y").ok()?;
+    Some(date.format("%Y-%m-%d %H:%M:%S").to_string())
+}
+
 pub fn sign_ca(
     id: &str,
     cacert: &PathBuf,
     cakey: &PathBuf,
+    pw: Option<&str>,
     key: &PathBuf,
     cert: &PathBuf,
 ) -> Result<(), Error> {
-    let conf = conf_path(&cert);
+    let conf = conf_path(cert);
     fs::write(&conf, format!("dn=cn={}
 expiration_days=365
 ca
@@ -72,14 +168,18 @@ crl_signing_key
 signing_key
 tls_www_client
 tls_www_server", &id))?;
-    let status = Command::new("/usr/bin/certtool")
+    let mut certtool = Command::new("/usr/bin/certtool");
+    match pw {
+        Some(pw) => certtool.env("GNUTLS_PIN", pw),
+        None     => certtool.arg("--ask-pass"),
+    };
+    let status = certtool
         .arg("--generate-certificate")
         .arg("--template").arg(&conf)
-        .arg("--ask-pass")
-        .arg("--load-privkey").arg(&key)
-        .arg("--load-ca-certificate").arg(&cacert)
-        .arg("--load-ca-privkey").arg(&cakey)
-        .arg("--outfile").arg(&cert)
+        .arg("--load-privkey").arg(key)
+        .arg("--load-ca-certificate").arg(cacert)
+        .arg("--load-ca-privkey").arg(cakey)
+        .arg("--outfile").arg(cert)
         .status()?;
     fs::remove_file(conf)?;
     if !status.success() {
@@ -95,7 +195,7 @@ pub fn sign(
     csr: &PathBuf,
     cert: &PathBuf,
 ) -> Result<(), Error> {
-    let conf = conf_path(&cert);
+    let conf = conf_path(cert);
     fs::write(&conf, format!("dn=cn={}
 expiration_days=365
 signing_key
@@ -105,10 +205,10 @@ path_len=2", &id))?;
     let status = Command::new("/usr/bin/certtool")
         .arg("--generate-certificate")
         .arg("--template").arg(&conf)
-        .arg("--load-request").arg(&csr)
-        .arg("--load-ca-certificate").arg(&cacert)
-        .arg("--load-ca-privkey").arg(&cakey)
-        .arg("--outfile").arg(&cert)
+        .arg("--load-request").arg(csr)
+        .arg("--load-ca-certificate").arg(cacert)
+        .arg("--load-ca-privkey").arg(cakey)
+        .arg("--outfile").arg(cert)
         .status()?;
     fs::remove_file(conf)?;
     if !status.success() {
@@ -116,3 +216,21 @@ path_len=2", &id))?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_not_after() {
+        let info = "X.509 Certificate Information:
+\tVersion: 3
+\tValidity:
+\t\tNot Before: Mon Oct 19 08:04:39 UTC 2026
+\t\tNot After: Sat Oct 18 08:04:39 UTC 2031
+\tSubject: CN=prod
+";
+        assert_eq!(parse_not_after(info).unwrap(), "2031-10-18 08:04:39");
+        assert!(parse_not_after("Subject: CN=prod\n").is_none());
+    }
+}